@@ -0,0 +1,458 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{
+    jit_function::{JitFn, JitFunction},
+    page_manage::{flush_icache, PageError, PageHandle, PageSize},
+};
+
+/// A range carved out of a `CodeCache` region.
+/// It is not `Clone`, give it back with `CodeCache::free` exactly once.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// the `CodeCache::id` it was carved out of
+    cache: usize,
+    region: usize,
+    offset: usize,
+    len: usize,
+}
+
+impl CodeBlock {
+    #[inline]
+    pub fn region(&self) -> usize {
+        self.region
+    }
+
+    /// from the start of the region
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    #[inline]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CodeCacheStats {
+    pub regions: usize,
+    pub reserved_bytes: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    /// 0.0 = all free bytes are in one block, close to 1.0 = free bytes are scattered.
+    pub fragmentation: f64,
+}
+
+#[derive(Debug)]
+struct Region {
+    page: PageHandle,
+    /// (offset, len), sorted by offset and coalesced
+    free_list: Vec<(usize, usize)>,
+}
+
+impl Region {
+    fn try_new(size: usize, page_size: PageSize, guarded: bool) -> Result<Self, PageError> {
+        let page = if guarded {
+            PageHandle::try_new_dual_guarded(size, page_size)?
+        } else {
+            PageHandle::try_new_dual(size, page_size)?
+        };
+        let free_list = vec![(0, page.cap())];
+        Ok(Region { page, free_list })
+    }
+
+    /// first fit, the alignment padding stays in the free list.
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let (i, start) = self
+            .free_list
+            .iter()
+            .enumerate()
+            .find_map(|(i, &(off, len))| {
+                let start = align_up(off, align);
+                if start + size <= off + len {
+                    Some((i, start))
+                } else {
+                    None
+                }
+            })?;
+        let (off, len) = self.free_list.remove(i);
+        let end = start + size;
+        if end < off + len {
+            self.free_list.insert(i, (end, off + len - end));
+        }
+        if off < start {
+            self.free_list.insert(i, (off, start - off));
+        }
+        Some(start)
+    }
+
+    /// `offset..offset + len` is inside the region and not in the free list.
+    fn is_allocated(&self, offset: usize, len: usize) -> bool {
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= self.page.cap() => end,
+            _ => return false,
+        };
+        self.free_list
+            .iter()
+            .all(|&(off, l)| off + l <= offset || end <= off)
+    }
+
+    fn free(&mut self, offset: usize, len: usize) {
+        let i = self.free_list.partition_point(|&(off, _)| off < offset);
+        self.free_list.insert(i, (offset, len));
+        // merge with next
+        if i + 1 < self.free_list.len() && offset + len == self.free_list[i + 1].0 {
+            self.free_list[i].1 += self.free_list[i + 1].1;
+            self.free_list.remove(i + 1);
+        }
+        // merge with prev
+        if i > 0 && self.free_list[i - 1].0 + self.free_list[i - 1].1 == offset {
+            self.free_list[i - 1].1 += self.free_list[i].1;
+            self.free_list.remove(i);
+        }
+    }
+}
+
+#[inline]
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Carves many functions out of shared executable regions.
+///
+/// Regions are dual mapped `PageHandle`s, code is written through the RW view
+/// while already installed code in the same region keeps running from the RX view.
+#[derive(Debug)]
+pub struct CodeCache {
+    /// unique per cache, blocks of another cache are rejected by `free`
+    id: usize,
+    page_size: PageSize,
+    region_size: usize,
+    guarded: bool,
+    regions: Vec<Region>,
+    used_bytes: usize,
+}
+
+impl CodeCache {
+    pub fn new(page_size: PageSize, region_size: usize) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        CodeCache {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            page_size,
+            region_size: page_size.round_up(region_size),
+            guarded: false,
+            regions: vec![],
+            used_bytes: 0,
+        }
+    }
+
+    /// every region is surrounded by guard pages, see `PageHandle::try_new_dual_guarded`.
+    pub fn new_guarded(page_size: PageSize, region_size: usize) -> Self {
+        CodeCache {
+            guarded: true,
+            ..Self::new(page_size, region_size)
+        }
+    }
+
+    #[inline]
+    pub fn alloc(&mut self, size: usize, align: usize) -> CodeBlock {
+        self.try_alloc(size, align).unwrap()
+    }
+
+    /// `align` must be a power of two and not bigger than the page size.
+    pub fn try_alloc(&mut self, size: usize, align: usize) -> Result<CodeBlock, PageError> {
+        assert!(
+            align.is_power_of_two(),
+            "CodeCache::alloc: align must be power of two"
+        );
+        assert!(
+            align <= self.page_size.0,
+            "CodeCache::alloc: align must be <= page size"
+        );
+        let size = size.max(1);
+        for (region, r) in self.regions.iter_mut().enumerate() {
+            if let Some(offset) = r.alloc(size, align) {
+                self.used_bytes += size;
+                return Ok(CodeBlock {
+                    cache: self.id,
+                    region,
+                    offset,
+                    len: size,
+                });
+            }
+        }
+        let mut r = Region::try_new(self.region_size.max(size), self.page_size, self.guarded)?;
+        let offset = r.alloc(size, align).unwrap();
+        self.regions.push(r);
+        self.used_bytes += size;
+        Ok(CodeBlock {
+            cache: self.id,
+            region: self.regions.len() - 1,
+            offset,
+            len: size,
+        })
+    }
+
+    #[inline]
+    pub fn install(&mut self, src: &[u8], align: usize) -> CodeBlock {
+        self.try_install(src, align).unwrap()
+    }
+
+    /// alloc and copy `src` into the block.
+    pub fn try_install(&mut self, src: &[u8], align: usize) -> Result<CodeBlock, PageError> {
+        let block = self.try_alloc(src.len(), align)?;
+        unsafe {
+            std::ptr::copy(src.as_ptr(), self.get_write_ptr(&block), src.len());
+        }
+        flush_icache(self.get_ptr(&block), src.len());
+        Ok(block)
+    }
+
+    /// `try_install` and record the code under `name` for the profiler, see `crate::profiler`.
+    pub fn try_install_named(
+        &mut self,
+        src: &[u8],
+        align: usize,
+        name: &str,
+    ) -> Result<CodeBlock, PageError> {
+        let block = self.try_install(src, align)?;
+        let _ = unsafe { crate::profiler::record_code(name, self.get_ptr(&block), src.len()) };
+        Ok(block)
+    }
+
+    /// Describe the block to gdb/lldb, unregistered when the block is freed.
+    #[cfg(target_pointer_width = "64")]
+    pub fn register_gdb(&self, block: &CodeBlock, info: &crate::gdb_jit::DebugInfo) {
+        crate::gdb_jit::register_code(self.get_ptr(block), block.len, info);
+    }
+
+    /// Give the block back, its gdb and trap registrations are dropped with it.
+    /// `Err(OutOfRange)` when the block is not allocated in this cache, nothing is freed then.
+    pub fn free(&mut self, block: CodeBlock) -> Result<(), PageError> {
+        let allocated = block.cache == self.id
+            && self
+                .regions
+                .get(block.region)
+                .is_some_and(|r| r.is_allocated(block.offset, block.len));
+        if !allocated {
+            return Err(PageError::OutOfRange {
+                offset: block.offset,
+                len: block.len,
+            });
+        }
+        #[cfg(target_pointer_width = "64")]
+        crate::gdb_jit::unregister_range(self.get_ptr(&block), block.len);
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        crate::trap::unregister_range(self.get_ptr(&block), block.len);
+        self.used_bytes -= block.len;
+        self.regions[block.region].free(block.offset, block.len);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get_ptr(&self, block: &CodeBlock) -> *const u8 {
+        unsafe { self.regions[block.region].page.get_ptr().add(block.offset) }
+    }
+
+    /// Typed entry at `offset` into the block, it can not outlive the cache.
+    ///
+    /// # Safety
+    /// the code at `offset` must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(
+        &self,
+        block: &CodeBlock,
+        offset: usize,
+    ) -> JitFunction<'_, F> {
+        assert!(offset < block.len, "get_function offset invalid");
+        JitFunction::from_raw(self.get_ptr(block).add(offset))
+    }
+
+    #[inline]
+    pub fn get_write_ptr(&self, block: &CodeBlock) -> *mut u8 {
+        let page = &self.regions[block.region].page;
+        unsafe { page.get_write_ptr().unwrap().add(block.offset) }
+    }
+
+    pub fn stats(&self) -> CodeCacheStats {
+        let mut stats = CodeCacheStats {
+            regions: self.regions.len(),
+            used_bytes: self.used_bytes,
+            ..Default::default()
+        };
+        for r in self.regions.iter() {
+            stats.reserved_bytes += r.page.cap();
+            for &(_, len) in r.free_list.iter() {
+                stats.free_bytes += len;
+                stats.free_blocks += 1;
+                stats.largest_free_block = stats.largest_free_block.max(len);
+            }
+        }
+        if stats.free_bytes != 0 {
+            stats.fragmentation = 1.0 - stats.largest_free_block as f64 / stats.free_bytes as f64;
+        }
+        stats
+    }
+}
+
+/// A `CodeCache` shared between threads.
+///
+/// Any thread can install finished code, the lock is only held to carve out the block,
+/// the copy happens outside of it.
+/// Installed code is handed out as `SharedCode`, which frees the block when the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct SharedCodeCache {
+    cache: Arc<Mutex<CodeCache>>,
+}
+
+impl SharedCodeCache {
+    pub fn new(page_size: PageSize, region_size: usize) -> Self {
+        SharedCodeCache::from(CodeCache::new(page_size, region_size))
+    }
+
+    pub fn new_guarded(page_size: PageSize, region_size: usize) -> Self {
+        SharedCodeCache::from(CodeCache::new_guarded(page_size, region_size))
+    }
+
+    #[inline]
+    pub fn install(&self, src: &[u8], align: usize) -> SharedCode {
+        self.try_install(src, align).unwrap()
+    }
+
+    pub fn try_install(&self, src: &[u8], align: usize) -> Result<SharedCode, PageError> {
+        self.try_install_with(src.len(), align, |_, buf| {
+            buf.copy_from_slice(src);
+            Ok(())
+        })
+    }
+
+    /// Install `len` bytes written by `emit`, for code that depends on its final address.
+    /// `emit` gets the executable address of the block and the writable bytes,
+    /// the block is freed again when it fails.
+    pub fn try_install_with<E: From<PageError>>(
+        &self,
+        len: usize,
+        align: usize,
+        emit: impl FnOnce(*const u8, &mut [u8]) -> Result<(), E>,
+    ) -> Result<SharedCode, E> {
+        let (block, ptr, write_ptr) = {
+            let mut cache = self.cache.lock().unwrap();
+            let block = cache.try_alloc(len, align)?;
+            let ptr = cache.get_ptr(&block);
+            let write_ptr = cache.get_write_ptr(&block);
+            (block, ptr, write_ptr)
+        };
+        let code = SharedCode(Arc::new(SharedCodeInner {
+            cache: self.cache.clone(),
+            block: Some(block),
+            ptr,
+            write_ptr,
+            len,
+        }));
+        // the block is only reachable from here until it is returned
+        emit(ptr, unsafe {
+            std::slice::from_raw_parts_mut(write_ptr, len)
+        })?;
+        flush_icache(ptr, len);
+        Ok(code)
+    }
+
+    /// `try_install` and record the code under `name` for the profiler, see `crate::profiler`.
+    pub fn try_install_named(
+        &self,
+        src: &[u8],
+        align: usize,
+        name: &str,
+    ) -> Result<SharedCode, PageError> {
+        let code = self.try_install(src, align)?;
+        let _ = unsafe { crate::profiler::record_code(name, code.get_ptr(), code.len()) };
+        Ok(code)
+    }
+
+    pub fn stats(&self) -> CodeCacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+}
+
+impl From<CodeCache> for SharedCodeCache {
+    fn from(cache: CodeCache) -> Self {
+        SharedCodeCache {
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SharedCodeInner {
+    cache: Arc<Mutex<CodeCache>>,
+    /// `None` only while dropping
+    block: Option<CodeBlock>,
+    ptr: *const u8,
+    write_ptr: *mut u8,
+    len: usize,
+}
+
+// the code is immutable after installation except for patch sites, and owned through `cache`.
+unsafe impl Send for SharedCodeInner {}
+unsafe impl Sync for SharedCodeInner {}
+
+impl Drop for SharedCodeInner {
+    fn drop(&mut self) {
+        if let Some(block) = self.block.take() {
+            // a poisoned cache still owns the region, leak the block instead of panicking
+            if let Ok(mut cache) = self.cache.lock() {
+                let _ = cache.free(block);
+            }
+        }
+    }
+}
+
+/// Installed code from a `SharedCodeCache`, cheap to clone and send to other threads.
+/// The block stays executable as long as any clone is alive.
+#[derive(Debug, Clone)]
+pub struct SharedCode(Arc<SharedCodeInner>);
+
+impl SharedCode {
+    #[inline]
+    pub fn get_ptr(&self) -> *const u8 {
+        self.0.ptr
+    }
+
+    #[inline]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// the writable view of the code, only for rewriting patch sites (`crate::patch_site`).
+    #[inline]
+    pub fn get_write_ptr(&self) -> *mut u8 {
+        self.0.write_ptr
+    }
+
+    /// Describe the code to gdb/lldb, unregistered when the last clone is dropped.
+    #[cfg(target_pointer_width = "64")]
+    pub fn register_gdb(&self, info: &crate::gdb_jit::DebugInfo) {
+        crate::gdb_jit::register_code(self.get_ptr(), self.len(), info);
+    }
+
+    /// Typed entry at `offset`, it can not outlive this handle.
+    ///
+    /// # Safety
+    /// the code at `offset` must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(&self, offset: usize) -> JitFunction<'_, F> {
+        assert!(offset < self.len(), "get_function offset invalid");
+        JitFunction::from_raw(self.get_ptr().add(offset))
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{
+    jit_function::{JitFn, JitFunction},
+    page_manage::{flush_icache, PageError, PageHandle, PageSize, Protection},
+};
+
+/// Where the sections of a `CodeModule` go, as offsets from the start of the code.
+///
+/// ```text
+/// | code (RX) | rodata (R) | rwdata (RW) |
+/// ```
+///
+/// Every section starts on a page boundary and the whole module is one mapping,
+/// so the layout is known before the code is generated
+/// and code can reach its data with rel32 (RIP/PC-relative) displacements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModuleLayout {
+    pub code_len: usize,
+    pub rodata_offset: usize,
+    pub rodata_len: usize,
+    pub rwdata_offset: usize,
+    pub rwdata_len: usize,
+    /// total size of the mapping
+    pub size: usize,
+}
+
+impl ModuleLayout {
+    pub fn new(page_size: PageSize, code_len: usize, rodata_len: usize, rwdata_len: usize) -> Self {
+        let rodata_offset = page_size.round_up(code_len);
+        let rwdata_offset = rodata_offset + page_size.round_up(rodata_len);
+        let size = (rwdata_offset + page_size.round_up(rwdata_len)).max(page_size.0);
+        ModuleLayout {
+            code_len,
+            rodata_offset,
+            rodata_len,
+            rwdata_offset,
+            rwdata_len,
+            size,
+        }
+    }
+
+    /// whether each section fits into its part of the layout
+    pub fn fits(&self, code_len: usize, rodata_len: usize, rwdata_len: usize) -> bool {
+        self.code_len <= self.rodata_offset
+            && self.rodata_offset <= self.rwdata_offset
+            && self.rwdata_offset <= self.size
+            && code_len <= self.rodata_offset
+            && rodata_len <= self.rwdata_offset - self.rodata_offset
+            && rwdata_len <= self.size - self.rwdata_offset
+    }
+
+    /// The displacement from `from` to `to`, both offsets into the module.
+    /// For x86_64 RIP-relative operands `from` is the end of the instruction.
+    #[inline]
+    pub fn rel32(from: usize, to: usize) -> Option<i32> {
+        i32::try_from(to as i64 - from as i64).ok()
+    }
+}
+
+/// Code with its constants and mutable globals next to it.
+///
+/// The code is RX, the rodata read-only and the rwdata stays writable for the module's lifetime.
+#[derive(Debug)]
+pub struct CodeModule {
+    page: PageHandle,
+    layout: ModuleLayout,
+}
+
+impl CodeModule {
+    #[inline]
+    pub fn new(page_size: PageSize, code: &[u8], rodata: &[u8], rwdata: &[u8]) -> Self {
+        Self::try_new(page_size, code, rodata, rwdata).unwrap()
+    }
+
+    pub fn try_new(
+        page_size: PageSize,
+        code: &[u8],
+        rodata: &[u8],
+        rwdata: &[u8],
+    ) -> Result<Self, PageError> {
+        let layout = ModuleLayout::new(page_size, code.len(), rodata.len(), rwdata.len());
+        Self::try_from_layout(page_size, layout, code, rodata, rwdata)
+    }
+
+    /// `layout` is usually computed ahead with `ModuleLayout::new` to generate the code,
+    /// each section must fit into its part of the layout, `PageError::Layout` otherwise.
+    pub fn try_from_layout(
+        page_size: PageSize,
+        layout: ModuleLayout,
+        code: &[u8],
+        rodata: &[u8],
+        rwdata: &[u8],
+    ) -> Result<Self, PageError> {
+        if !layout.fits(code.len(), rodata.len(), rwdata.len()) {
+            return Err(PageError::Layout);
+        }
+        let mut page = PageHandle::try_new(layout.size, page_size)?;
+        let ptr = page.get_mut_ptr();
+        unsafe {
+            std::ptr::copy(code.as_ptr(), ptr, code.len());
+            std::ptr::copy(rodata.as_ptr(), ptr.add(layout.rodata_offset), rodata.len());
+            std::ptr::copy(rwdata.as_ptr(), ptr.add(layout.rwdata_offset), rwdata.len());
+        }
+        page.try_protect(0, layout.rodata_offset, Protection::ReadExecute)?;
+        page.try_protect(
+            layout.rodata_offset,
+            layout.rwdata_offset - layout.rodata_offset,
+            Protection::ReadOnly,
+        )?;
+        flush_icache(ptr, layout.code_len);
+        // `len` bounds `record_symbol` to the code
+        page.truncate(layout.code_len);
+        Ok(CodeModule { page, layout })
+    }
+
+    #[inline]
+    pub fn layout(&self) -> &ModuleLayout {
+        &self.layout
+    }
+
+    /// see `PageHandle::record_symbol`, the range is inside the code.
+    #[inline]
+    pub fn record_symbol(&self, name: &str, offset: usize, len: usize) {
+        self.page.record_symbol(name, offset, len)
+    }
+
+    #[inline]
+    pub fn code_ptr(&self) -> *const u8 {
+        self.page.get_ptr()
+    }
+
+    #[inline]
+    pub fn rodata_ptr(&self) -> *const u8 {
+        unsafe { self.page.get_ptr().add(self.layout.rodata_offset) }
+    }
+
+    #[inline]
+    pub fn rwdata_ptr(&self) -> *mut u8 {
+        unsafe { self.page.get_mut_ptr().add(self.layout.rwdata_offset) }
+    }
+
+    /// Typed entry at `offset` into the code, it can not outlive the module.
+    /// `None` when `offset` is outside the code.
+    ///
+    /// # Safety
+    /// the code at `offset` must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(&self, offset: usize) -> Option<JitFunction<'_, F>> {
+        if offset >= self.layout.code_len {
+            return None;
+        }
+        Some(JitFunction::from_raw(self.code_ptr().add(offset)))
+    }
+}
@@ -0,0 +1,268 @@
+//! Background compilation.
+//!
+//! A `CompileService` runs compile jobs on worker threads:
+//! the job builds an `InstBuffer`, the worker links it at its final address,
+//! installs it into a `SharedCodeCache` and hands the result to the job's callback.
+//! The queue is bounded, jobs can be cancelled until they are installed.
+
+use std::{
+    collections::VecDeque,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use crate::{
+    code_cache::{SharedCode, SharedCodeCache},
+    insts::{x86_64::inst_dump_buf::InstBuffer, LinkError},
+    page_manage::PageError,
+};
+
+/// Builds the code of one function, runs on a worker thread.
+pub type CompileJob = Box<dyn FnOnce() -> InstBuffer + Send>;
+
+/// Receives the installed code or why there is none.
+/// Runs on a worker thread, or on the thread dropping the service for jobs still queued.
+pub type InstallCallback = Box<dyn FnOnce(Result<SharedCode, CompileError>) + Send>;
+
+#[derive(Debug, Clone)]
+pub enum CompileError {
+    /// a jump to an undefined label
+    Link(LinkError),
+    /// allocating executable memory failed
+    Page(PageError),
+    /// cancelled with `CompileTicket::cancel` or dropped with the service
+    Cancelled,
+    /// the compile job panicked
+    Panicked,
+}
+
+impl From<LinkError> for CompileError {
+    fn from(e: LinkError) -> Self {
+        CompileError::Link(e)
+    }
+}
+
+impl From<PageError> for CompileError {
+    fn from(e: PageError) -> Self {
+        CompileError::Page(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    /// the queue holds `queue_capacity` jobs already
+    QueueFull,
+    /// the service is shutting down
+    Shutdown,
+}
+
+/// Handle to a submitted job.
+#[derive(Debug, Clone)]
+pub struct CompileTicket(Arc<AtomicBool>);
+
+impl CompileTicket {
+    /// The callback gets `Err(CompileError::Cancelled)` unless the code is installed already.
+    #[inline]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+struct Job {
+    cancelled: Arc<AtomicBool>,
+    compile: CompileJob,
+    on_install: InstallCallback,
+    align: usize,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+struct Shared {
+    cache: SharedCodeCache,
+    capacity: usize,
+    queue: Mutex<Queue>,
+    /// signalled when a job is queued or on shutdown
+    not_empty: Condvar,
+    /// signalled when a job is taken off the queue or on shutdown
+    not_full: Condvar,
+}
+
+/// A pool of compile workers, see the module documentation.
+///
+/// Dropping it cancels the queued jobs, waits for the running ones and joins the workers.
+pub struct CompileService {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for CompileService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompileService")
+            .field("workers", &self.workers.len())
+            .field("capacity", &self.shared.capacity)
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl CompileService {
+    /// `workers` threads installing into `cache`, at most `queue_capacity` jobs waiting.
+    pub fn new(cache: SharedCodeCache, workers: usize, queue_capacity: usize) -> Self {
+        assert!(workers > 0, "CompileService: no workers");
+        assert!(queue_capacity > 0, "CompileService: queue_capacity is 0");
+        let shared = Arc::new(Shared {
+            cache,
+            capacity: queue_capacity,
+            queue: Mutex::new(Queue::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        });
+        let workers = (0..workers)
+            .map(|i| {
+                let shared = shared.clone();
+                std::thread::Builder::new()
+                    .name(format!("emei-compile-{}", i))
+                    .spawn(move || worker(&shared))
+                    .expect("CompileService: spawning a worker failed")
+            })
+            .collect();
+        CompileService { shared, workers }
+    }
+
+    /// Queue a job, fails instead of waiting when the queue is full.
+    pub fn try_submit(
+        &self,
+        compile: CompileJob,
+        align: usize,
+        on_install: InstallCallback,
+    ) -> Result<CompileTicket, SubmitError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.shutdown {
+            return Err(SubmitError::Shutdown);
+        }
+        if queue.jobs.len() >= self.shared.capacity {
+            return Err(SubmitError::QueueFull);
+        }
+        Ok(self.push(&mut queue, compile, align, on_install))
+    }
+
+    /// Queue a job, waits while the queue is full.
+    pub fn submit(
+        &self,
+        compile: CompileJob,
+        align: usize,
+        on_install: InstallCallback,
+    ) -> Result<CompileTicket, SubmitError> {
+        let mut queue = self
+            .shared
+            .not_full
+            .wait_while(self.shared.queue.lock().unwrap(), |q| {
+                !q.shutdown && q.jobs.len() >= self.shared.capacity
+            })
+            .unwrap();
+        if queue.shutdown {
+            return Err(SubmitError::Shutdown);
+        }
+        Ok(self.push(&mut queue, compile, align, on_install))
+    }
+
+    fn push(
+        &self,
+        queue: &mut Queue,
+        compile: CompileJob,
+        align: usize,
+        on_install: InstallCallback,
+    ) -> CompileTicket {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        queue.jobs.push_back(Job {
+            cancelled: cancelled.clone(),
+            compile,
+            on_install,
+            align,
+        });
+        self.shared.not_empty.notify_one();
+        CompileTicket(cancelled)
+    }
+
+    /// number of jobs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().jobs.len()
+    }
+}
+
+impl Drop for CompileService {
+    fn drop(&mut self) {
+        let pending = match self.shared.queue.lock() {
+            Ok(mut queue) => {
+                queue.shutdown = true;
+                std::mem::take(&mut queue.jobs)
+            }
+            Err(_) => VecDeque::new(),
+        };
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+        for job in pending {
+            (job.on_install)(Err(CompileError::Cancelled));
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared
+                .not_empty
+                .wait_while(shared.queue.lock().unwrap(), |q| {
+                    !q.shutdown && q.jobs.is_empty()
+                })
+                .unwrap();
+            match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => return,
+            }
+        };
+        shared.not_full.notify_one();
+        let r = run(&shared.cache, &job.cancelled, job.compile, job.align);
+        (job.on_install)(r);
+    }
+}
+
+fn run(
+    cache: &SharedCodeCache,
+    cancelled: &AtomicBool,
+    compile: CompileJob,
+    align: usize,
+) -> Result<SharedCode, CompileError> {
+    if cancelled.load(Ordering::Acquire) {
+        return Err(CompileError::Cancelled);
+    }
+    let buf = catch_unwind(AssertUnwindSafe(compile)).map_err(|_| CompileError::Panicked)?;
+    // the length does not depend on the base address
+    let mut code = vec![];
+    buf.dump(0, &mut code)?;
+    if cancelled.load(Ordering::Acquire) {
+        return Err(CompileError::Cancelled);
+    }
+    cache.try_install_with(code.len(), align, |ptr, dst| {
+        code.clear();
+        buf.dump(ptr as u64, &mut code)?;
+        dst.copy_from_slice(&code);
+        Ok(())
+    })
+}
@@ -0,0 +1,111 @@
+//! Function versioning through an indirection table.
+//!
+//! Every logical function gets a stable slot holding the entry of its current version.
+//! Generated code calls through the slot (`call_slot`), so replacing the implementation
+//! is a single atomic store and no caller has to be patched.
+//! The replaced version is retired to a `Reclaimer` and freed once no thread can still run it.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use crate::{
+    code_cache::SharedCode,
+    jit_function::{JitFn, JitFunction},
+    reclaim::Reclaimer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FunctionId(pub usize);
+
+/// A fixed number of slots, see the module documentation.
+#[derive(Debug)]
+pub struct FunctionTable {
+    /// boxed, generated code holds the slot addresses
+    slots: Box<[AtomicUsize]>,
+    /// the installed versions, index = `FunctionId`
+    versions: Mutex<Vec<SharedCode>>,
+    reclaimer: Reclaimer,
+}
+
+impl FunctionTable {
+    pub fn new(capacity: usize, reclaimer: Reclaimer) -> Self {
+        FunctionTable {
+            slots: (0..capacity).map(|_| AtomicUsize::new(0)).collect(),
+            versions: Mutex::new(Vec::with_capacity(capacity)),
+            reclaimer,
+        }
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[inline]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.versions.lock().unwrap().len()
+    }
+
+    /// Give `code` a new slot, `None` when the table is full.
+    pub fn define(&self, code: SharedCode) -> Option<FunctionId> {
+        let mut versions = self.versions.lock().unwrap();
+        let id = versions.len();
+        let slot = self.slots.get(id)?;
+        slot.store(code.get_ptr() as usize, Ordering::Release);
+        versions.push(code);
+        Some(FunctionId(id))
+    }
+
+    /// Point the slot at `code`, the old version is retired to the reclaimer.
+    /// Calls already inside the old version finish there, new calls go to `code`.
+    pub fn replace(&self, id: FunctionId, code: SharedCode) {
+        let mut versions = self.versions.lock().unwrap();
+        let old = std::mem::replace(&mut versions[id.0], code);
+        self.slots[id.0].store(versions[id.0].get_ptr() as usize, Ordering::Release);
+        drop(versions);
+        self.reclaimer.retire(old);
+    }
+
+    /// the entry of the current version.
+    #[inline]
+    pub fn get_ptr(&self, id: FunctionId) -> *const u8 {
+        self.slots[id.0].load(Ordering::Acquire) as *const u8
+    }
+
+    /// the address generated code loads the entry from, stable for the table's lifetime.
+    #[inline]
+    pub fn slot_ptr(&self, id: FunctionId) -> *const usize {
+        self.slots[id.0].as_ptr() as *const usize
+    }
+
+    /// The current version as a typed entry.
+    /// It stays valid while the calling thread does not pass a quiescent state, see `crate::reclaim`.
+    ///
+    /// # Safety
+    /// every version of `id` must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(&self, id: FunctionId) -> JitFunction<'_, F> {
+        JitFunction::from_raw(self.get_ptr(id))
+    }
+
+    /// `mov r11, slot; call [r11]`, r11 is clobbered.
+    #[cfg(target_arch = "x86_64")]
+    pub fn call_slot(&self, id: FunctionId) -> Vec<u8> {
+        let mut code = vec![0x49, 0xbb];
+        code.extend((self.slot_ptr(id) as u64).to_le_bytes());
+        code.extend([0x41, 0xff, 0x13]);
+        code
+    }
+
+    /// `mov r11, slot; jmp [r11]` for tail calls, r11 is clobbered.
+    #[cfg(target_arch = "x86_64")]
+    pub fn jmp_slot(&self, id: FunctionId) -> Vec<u8> {
+        let mut code = vec![0x49, 0xbb];
+        code.extend((self.slot_ptr(id) as u64).to_le_bytes());
+        code.extend([0x41, 0xff, 0x23]);
+        code
+    }
+}
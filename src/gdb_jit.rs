@@ -0,0 +1,474 @@
+//! GDB JIT interface, makes installed code visible to gdb and lldb.
+//!
+//! An in-memory ELF image describing the code (symbols, optional line table)
+//! is linked into `__jit_debug_descriptor` and announced through `__jit_debug_register_code`.
+//!
+//! The debugger finds those two by their unmangled names, they are only exported with the
+//! `gdb-jit` feature: another JIT in the same binary (LLVM, wasmtime...) may already define them.
+//! Without it registration still works, but no debugger sees the code.
+
+use std::sync::{
+    atomic::{compiler_fence, AtomicBool, Ordering},
+    Mutex,
+};
+
+use lazy_static::lazy_static;
+
+#[cfg(target_arch = "x86_64")]
+pub const ELF_MACHINE: u16 = 62;
+#[cfg(target_arch = "x86")]
+pub const ELF_MACHINE: u16 = 3;
+#[cfg(target_arch = "aarch64")]
+pub const ELF_MACHINE: u16 = 183;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub const ELF_MACHINE: u16 = 243;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+)))]
+pub const ELF_MACHINE: u16 = 0;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugSymbol {
+    pub name: String,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// What the debugger learns about a piece of installed code.
+/// `lines` are (code offset, source line) pairs of `file`, only emitted when `file` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub symbols: Vec<DebugSymbol>,
+    pub file: Option<String>,
+    pub lines: Vec<(usize, u32)>,
+}
+
+impl DebugInfo {
+    /// one symbol covering the whole code.
+    pub fn from_symbol(name: &str, len: usize) -> Self {
+        DebugInfo {
+            symbols: vec![DebugSymbol {
+                name: name.to_string(),
+                offset: 0,
+                len,
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+///////////////////////////////////////////////////////
+// ELF image
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const STB_GLOBAL_STT_FUNC: u8 = 0x12;
+const TEXT_SECTION: u16 = 1;
+
+#[derive(Default)]
+struct Section {
+    name: u32,
+    ty: u32,
+    flags: u64,
+    addr: u64,
+    data: Vec<u8>,
+    /// size for SHT_NOBITS
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+#[derive(Default)]
+struct StrTab(Vec<u8>);
+
+impl StrTab {
+    fn new() -> Self {
+        StrTab(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let r = self.0.len() as u32;
+        self.0.extend(s.as_bytes());
+        self.0.push(0);
+        r
+    }
+}
+
+fn uleb128(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sleb128(buf: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// (.debug_abbrev, .debug_info, .debug_line), DWARF 2
+fn dwarf_sections(code: u64, len: u64, file: &str, lines: &[(usize, u32)]) -> [Vec<u8>; 3] {
+    const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+    const DW_AT_NAME: u64 = 0x03;
+    const DW_AT_STMT_LIST: u64 = 0x10;
+    const DW_AT_LOW_PC: u64 = 0x11;
+    const DW_AT_HIGH_PC: u64 = 0x12;
+    const DW_FORM_ADDR: u64 = 0x01;
+    const DW_FORM_DATA4: u64 = 0x06;
+    const DW_FORM_STRING: u64 = 0x08;
+
+    let mut abbrev = vec![];
+    for v in [
+        1,
+        DW_TAG_COMPILE_UNIT,
+        0,
+        DW_AT_NAME,
+        DW_FORM_STRING,
+        DW_AT_STMT_LIST,
+        DW_FORM_DATA4,
+        DW_AT_LOW_PC,
+        DW_FORM_ADDR,
+        DW_AT_HIGH_PC,
+        DW_FORM_ADDR,
+        0,
+        0,
+        0,
+    ] {
+        uleb128(&mut abbrev, v);
+    }
+
+    let mut die = vec![];
+    die.extend(2u16.to_ne_bytes());
+    die.extend(0u32.to_ne_bytes());
+    die.push(8);
+    uleb128(&mut die, 1);
+    die.extend(file.as_bytes());
+    die.push(0);
+    die.extend(0u32.to_ne_bytes());
+    die.extend(code.to_ne_bytes());
+    die.extend((code + len).to_ne_bytes());
+    let mut info = (die.len() as u32).to_ne_bytes().to_vec();
+    info.extend(die);
+
+    // header after the header_length field
+    let mut header = vec![1, 1, (-5i8) as u8, 14, 13];
+    header.extend([0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    header.push(0); // no include directories
+    header.extend(file.as_bytes());
+    header.extend([0, 0, 0, 0]); // dir, mtime, len
+    header.push(0);
+
+    let mut program = vec![0, 9, 2];
+    program.extend(code.to_ne_bytes());
+    let mut lines = lines.to_vec();
+    lines.sort();
+    let (mut pc, mut line) = (0u64, 1i64);
+    for (offset, l) in lines {
+        if offset as u64 > pc {
+            program.push(2); // DW_LNS_advance_pc
+            uleb128(&mut program, offset as u64 - pc);
+            pc = offset as u64;
+        }
+        if l as i64 != line {
+            program.push(3); // DW_LNS_advance_line
+            sleb128(&mut program, l as i64 - line);
+            line = l as i64;
+        }
+        program.push(1); // DW_LNS_copy
+    }
+    if len > pc {
+        program.push(2);
+        uleb128(&mut program, len - pc);
+    }
+    program.extend([0, 1, 1]); // DW_LNE_end_sequence
+
+    let mut unit = 2u16.to_ne_bytes().to_vec();
+    unit.extend((header.len() as u32).to_ne_bytes());
+    unit.extend(header);
+    unit.extend(program);
+    let mut line = (unit.len() as u32).to_ne_bytes().to_vec();
+    line.extend(unit);
+
+    [abbrev, info, line]
+}
+
+/// Build the ELF64 object gdb reads, `code..code + len` is a `.text` without file contents.
+pub fn build_elf(code: *const u8, len: usize, info: &DebugInfo) -> Vec<u8> {
+    let code = code as u64;
+    let mut shstrtab = StrTab::new();
+    let mut strtab = StrTab::new();
+
+    let mut symtab = vec![0u8; 24];
+    for sym in info.symbols.iter() {
+        symtab.extend(strtab.add(&sym.name).to_ne_bytes());
+        symtab.push(STB_GLOBAL_STT_FUNC);
+        symtab.push(0);
+        symtab.extend(TEXT_SECTION.to_ne_bytes());
+        symtab.extend((sym.offset as u64).to_ne_bytes());
+        symtab.extend((sym.len as u64).to_ne_bytes());
+    }
+
+    let mut sections = vec![
+        Section::default(),
+        Section {
+            name: shstrtab.add(".text"),
+            ty: SHT_NOBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            addr: code,
+            size: len as u64,
+            align: 16,
+            ..Default::default()
+        },
+        Section {
+            name: shstrtab.add(".symtab"),
+            ty: SHT_SYMTAB,
+            data: symtab,
+            link: 3,
+            info: 1,
+            align: 8,
+            entsize: 24,
+            ..Default::default()
+        },
+        Section {
+            name: shstrtab.add(".strtab"),
+            ty: SHT_STRTAB,
+            data: strtab.0,
+            align: 1,
+            ..Default::default()
+        },
+    ];
+    if let Some(file) = info.file.as_ref() {
+        let [abbrev, debug_info, line] = dwarf_sections(code, len as u64, file, &info.lines);
+        for (name, data) in [
+            (".debug_abbrev", abbrev),
+            (".debug_info", debug_info),
+            (".debug_line", line),
+        ] {
+            sections.push(Section {
+                name: shstrtab.add(name),
+                ty: SHT_PROGBITS,
+                data,
+                align: 1,
+                ..Default::default()
+            });
+        }
+    }
+    let shstrndx = sections.len() as u16;
+    sections.push(Section {
+        name: shstrtab.add(".shstrtab"),
+        ty: SHT_STRTAB,
+        align: 1,
+        ..Default::default()
+    });
+    sections.last_mut().unwrap().data = shstrtab.0;
+
+    // header, section contents, section headers
+    let mut offsets = vec![];
+    let mut body = vec![];
+    for s in sections.iter() {
+        while body.len() % 8 != 0 {
+            body.push(0);
+        }
+        offsets.push(64 + body.len() as u64);
+        body.extend(s.data.iter());
+    }
+    while body.len() % 8 != 0 {
+        body.push(0);
+    }
+    let shoff = 64 + body.len() as u64;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2];
+    elf.push(if cfg!(target_endian = "little") { 1 } else { 2 });
+    elf.push(1);
+    elf.resize(16, 0);
+    elf.extend(1u16.to_ne_bytes()); // ET_REL
+    elf.extend(ELF_MACHINE.to_ne_bytes());
+    elf.extend(1u32.to_ne_bytes());
+    elf.extend(0u64.to_ne_bytes()); // entry
+    elf.extend(0u64.to_ne_bytes()); // phoff
+    elf.extend(shoff.to_ne_bytes());
+    elf.extend(0u32.to_ne_bytes()); // flags
+    elf.extend(64u16.to_ne_bytes());
+    elf.extend(0u16.to_ne_bytes());
+    elf.extend(0u16.to_ne_bytes());
+    elf.extend(64u16.to_ne_bytes());
+    elf.extend((sections.len() as u16).to_ne_bytes());
+    elf.extend(shstrndx.to_ne_bytes());
+    elf.extend(body);
+
+    for (s, offset) in sections.iter().zip(offsets) {
+        let size = if s.ty == SHT_NOBITS {
+            s.size
+        } else {
+            s.data.len() as u64
+        };
+        elf.extend(s.name.to_ne_bytes());
+        elf.extend(s.ty.to_ne_bytes());
+        elf.extend(s.flags.to_ne_bytes());
+        elf.extend(s.addr.to_ne_bytes());
+        elf.extend(if s.ty == 0 { 0 } else { offset }.to_ne_bytes());
+        elf.extend(size.to_ne_bytes());
+        elf.extend(s.link.to_ne_bytes());
+        elf.extend(s.info.to_ne_bytes());
+        elf.extend(s.align.to_ne_bytes());
+        elf.extend(s.entsize.to_ne_bytes());
+    }
+    elf
+}
+
+///////////////////////////////////////////////////////
+// registration protocol
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// the debugger sets a breakpoint here and reads `__jit_debug_descriptor` when it is hit.
+#[cfg_attr(feature = "gdb-jit", no_mangle)]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg_attr(feature = "gdb-jit", no_mangle)]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: std::ptr::null_mut(),
+    first_entry: std::ptr::null_mut(),
+};
+
+struct Registered {
+    code: usize,
+    entry: Box<JitCodeEntry>,
+    _image: Vec<u8>,
+}
+
+// the entries are only touched under `REGISTERED`
+unsafe impl Send for Registered {}
+
+lazy_static! {
+    static ref REGISTERED: Mutex<Vec<Registered>> = Mutex::new(vec![]);
+}
+
+/// fast path for `unregister_code` from `Drop`
+static ANY_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Build the image for `code..code + len` and announce it to the debugger,
+/// it stays registered until `unregister_code(code)` or an `unregister_range` covering `code`.
+pub fn register_code(code: *const u8, len: usize, info: &DebugInfo) {
+    let image = build_elf(code, len, info);
+    let mut registered = REGISTERED.lock().unwrap();
+    let mut entry = Box::new(JitCodeEntry {
+        next_entry: std::ptr::null_mut(),
+        prev_entry: std::ptr::null_mut(),
+        symfile_addr: image.as_ptr(),
+        symfile_size: image.len() as u64,
+    });
+    unsafe {
+        let descriptor = &mut *std::ptr::addr_of_mut!(__jit_debug_descriptor);
+        let entry_ptr = &mut *entry as *mut JitCodeEntry;
+        entry.next_entry = descriptor.first_entry;
+        if !descriptor.first_entry.is_null() {
+            (*descriptor.first_entry).prev_entry = entry_ptr;
+        }
+        descriptor.first_entry = entry_ptr;
+        descriptor.relevant_entry = entry_ptr;
+        descriptor.action_flag = JIT_REGISTER_FN;
+        __jit_debug_register_code();
+        descriptor.action_flag = JIT_NOACTION;
+    }
+    registered.push(Registered {
+        code: code as usize,
+        entry,
+        _image: image,
+    });
+    ANY_REGISTERED.store(true, Ordering::Release);
+}
+
+/// Remove every image registered for `code`, no-op if there is none.
+pub fn unregister_code(code: *const u8) {
+    unregister_where(|start| start == code as usize);
+}
+
+/// Remove every image starting inside `start..start + len`, for memory about to be reused.
+pub fn unregister_range(start: *const u8, len: usize) {
+    let range = start as usize..start as usize + len;
+    unregister_where(|code| range.contains(&code));
+}
+
+fn unregister_where(matches: impl Fn(usize) -> bool) {
+    if !ANY_REGISTERED.load(Ordering::Acquire) {
+        return;
+    }
+    let mut registered = REGISTERED.lock().unwrap();
+    while let Some(i) = registered.iter().position(|r| matches(r.code)) {
+        let mut r = registered.swap_remove(i);
+        unsafe {
+            let descriptor = &mut *std::ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = &mut *r.entry;
+            if !entry.prev_entry.is_null() {
+                (*entry.prev_entry).next_entry = entry.next_entry;
+            } else {
+                descriptor.first_entry = entry.next_entry;
+            }
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = entry.prev_entry;
+            }
+            descriptor.relevant_entry = entry;
+            descriptor.action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            descriptor.action_flag = JIT_NOACTION;
+            descriptor.relevant_entry = std::ptr::null_mut();
+        }
+    }
+    if registered.is_empty() {
+        ANY_REGISTERED.store(false, Ordering::Release);
+    }
+}
+
+/// number of images currently linked into `__jit_debug_descriptor`.
+pub fn registered_count() -> usize {
+    REGISTERED.lock().unwrap().len()
+}
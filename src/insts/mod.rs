@@ -1,5 +1,114 @@
 // pub mod riscv;
 pub mod x86_64;
 
-#[derive(Debug, Clone)]
-pub struct LinkError(pub String);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// a jump to a label that is not defined
+    Undefined(String),
+    /// the label is out of reach of the jump's displacement, or its address overflows
+    OutOfRange(String),
+}
+
+impl LinkError {
+    pub fn label(&self) -> &str {
+        match self {
+            LinkError::Undefined(l) | LinkError::OutOfRange(l) => l,
+        }
+    }
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Undefined(l) => write!(f, "undefined label `{}`", l),
+            LinkError::OutOfRange(l) => write!(f, "label `{}` is out of range", l),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Where encoders write their bytes, the `*_to` encoders write into it without allocating.
+pub trait CodeSink {
+    fn put_byte(&mut self, byte: u8);
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.put_byte(b);
+        }
+    }
+
+    /// number of bytes put so far
+    fn position(&self) -> usize;
+}
+
+impl CodeSink for Vec<u8> {
+    #[inline]
+    fn put_byte(&mut self, byte: u8) {
+        self.push(byte);
+    }
+
+    #[inline]
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+
+    #[inline]
+    fn position(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A sink over a caller provided buffer.
+/// Bytes beyond its end are dropped, check `overflowed` after encoding.
+#[derive(Debug)]
+pub struct FixedBuf<'a> {
+    buf: &'a mut [u8],
+    /// bytes put, including dropped ones
+    pos: usize,
+}
+
+impl<'a> FixedBuf<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        FixedBuf { buf, pos: 0 }
+    }
+
+    /// the bytes written into the buffer
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.pos.min(self.buf.len())]
+    }
+
+    #[inline]
+    pub fn overflowed(&self) -> bool {
+        self.pos > self.buf.len()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.pos = 0;
+    }
+}
+
+impl<'a> CodeSink for FixedBuf<'a> {
+    #[inline]
+    fn put_byte(&mut self, byte: u8) {
+        if let Some(b) = self.buf.get_mut(self.pos) {
+            *b = byte;
+        }
+        self.pos += 1;
+    }
+
+    #[inline]
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        let start = self.pos.min(self.buf.len());
+        let end = (self.pos + bytes.len()).min(self.buf.len());
+        self.buf[start..end].copy_from_slice(&bytes[..end - start]);
+        self.pos += bytes.len();
+    }
+
+    #[inline]
+    fn position(&self) -> usize {
+        self.pos
+    }
+}
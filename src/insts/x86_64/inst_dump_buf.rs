@@ -4,9 +4,9 @@ use std::{
     ops::{AddAssign, DerefMut},
 };
 
-use crate::insts::LinkError;
+use crate::insts::{CodeSink, LinkError};
 
-use super::ImmByte;
+use super::{inst_list, ImmByte};
 
 /*
 #[derive(Debug, Clone, Default)]
@@ -14,6 +14,9 @@ pub struct CallInst(pub JumpInst);
 
 */
 
+/// An instruction whose trailing field refers to `label`.
+/// An 8, 16 or 32 bit field gets the displacement from the end of the instruction (rel8/rel16/rel32),
+/// a 64 bit field the absolute address.
 #[derive(Debug, Clone, Default)]
 pub struct JumpInst {
     pub opcodes: Vec<u8>,
@@ -45,10 +48,37 @@ impl JumpInst {
     }
 }
 
+/// An instruction with a `[rip + disp32]` operand (`Op1::Rip`) whose displacement points to `label`.
+#[derive(Debug, Clone, Default)]
+pub struct RipInst {
+    pub opcodes: Vec<u8>,
+    pub label: String,
+    pub modify_range: (usize, usize),
+}
+
+impl RipInst {
+    /// `opcodes` is encoded with `Op1::Rip`, `imm_byte` is the immediate following the displacement.
+    pub fn from(opcodes: Vec<u8>, imm_byte: Option<ImmByte>, label: String) -> Self {
+        let right = opcodes.len() - imm_byte.map(ImmByte::bytes).unwrap_or(0);
+        RipInst {
+            opcodes,
+            label,
+            modify_range: (right - 4, right),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.opcodes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum InstUnit {
     Inst(Vec<u8>),
     JumpInst(JumpInst),
+    RipInst(RipInst),
     // CallInst(CallInst),
 }
 
@@ -57,6 +87,7 @@ impl InstUnit {
         match self {
             InstUnit::Inst(inst) => inst.len(),
             InstUnit::JumpInst(jump_inst) => jump_inst.len(),
+            InstUnit::RipInst(rip_inst) => rip_inst.len(),
             // InstUnit::CallInst(call_inst) => call_inst.0.len(),
         }
     }
@@ -65,11 +96,45 @@ impl InstUnit {
     }
 }
 
+/// A region reserved by `InstBuffer::patchable`, offsets are from the start of the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchSite {
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// the widest region that can be rewritten with one atomic store
+pub const MAX_PATCH_SITE_LEN: usize = 8;
+
+/// the length of the first instruction of a patch site of `len` bytes,
+/// a rel32 call/jmp is 5 bytes and every rewrite keeps the boundary after it.
+pub fn patch_site_head(len: usize) -> usize {
+    len.min(5)
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct InstBuffer {
     pub buf: RefCell<Vec<InstUnit>>,
     pub label_buf: RefCell<HashMap<String, u32>>,
     pub offset: RefCell<u32>,
+    pub patch_sites: RefCell<HashMap<String, PatchSite>>,
+}
+
+/// a single nop instruction of `len` bytes.
+pub fn nop_fill(len: usize) -> Vec<u8> {
+    match len {
+        0 => vec![],
+        1 => inst_list::nop1(),
+        2 => inst_list::nop2(),
+        3 => inst_list::nop3(),
+        4 => inst_list::nop4(),
+        5 => inst_list::nop5(),
+        6 => inst_list::nop6(),
+        7 => inst_list::nop7(),
+        8 => inst_list::nop8(),
+        9 => inst_list::nop9(),
+        _ => panic!("nop_fill: no single nop of {} bytes", len),
+    }
 }
 
 impl InstBuffer {
@@ -96,29 +161,106 @@ impl InstBuffer {
         self.buf.borrow_mut().push(InstUnit::JumpInst(i));
     }
 
+    pub fn rip(&self, i: RipInst) {
+        self.offset
+            .borrow_mut()
+            .deref_mut()
+            .add_assign(i.len() as u32);
+        self.buf.borrow_mut().push(InstUnit::RipInst(i));
+    }
+
     pub fn label(&self, label: String) {
         self.label_buf
             .borrow_mut()
             .insert(label, *self.offset.borrow());
     }
 
-    pub fn dump(&self, base_addr: u32, buf: &mut Vec<u8>) -> Result<(), LinkError> {
+    /// Reserve `len` (1..=8) bytes under `name` to be rewritten after installation,
+    /// see `crate::patch_site`.
+    ///
+    /// The region is nops with the boundaries of `patch_site_head`, placed so it does not cross
+    /// an 8 byte boundary (nop padding is inserted before it when needed).
+    /// Install the code at least 8 byte aligned to keep that property.
+    pub fn patchable(&self, name: String, len: usize) -> PatchSite {
+        assert!(
+            (1..=MAX_PATCH_SITE_LEN).contains(&len),
+            "patchable: len must be 1..=8"
+        );
+        let offset = *self.offset.borrow() as usize;
+        let misalign = offset % MAX_PATCH_SITE_LEN;
+        if misalign + len > MAX_PATCH_SITE_LEN {
+            self.inst(nop_fill(MAX_PATCH_SITE_LEN - misalign));
+        }
+        let site = PatchSite {
+            offset: *self.offset.borrow(),
+            len: len as u32,
+        };
+        self.label(name.clone());
+        let head = patch_site_head(len);
+        let mut nops = nop_fill(head);
+        nops.extend(nop_fill(len - head));
+        self.inst(nops);
+        self.patch_sites.borrow_mut().insert(name, site);
+        site
+    }
+
+    pub fn patch_site(&self, name: &str) -> Option<PatchSite> {
+        self.patch_sites.borrow().get(name).copied()
+    }
+
+    fn label_offset(&self, label: &str) -> Result<u32, LinkError> {
+        self.label_buf
+            .borrow()
+            .get(label)
+            .cloned()
+            .ok_or_else(|| LinkError::Undefined(label.to_string()))
+    }
+
+    /// Link the buffer as if placed at `base_addr` and append it to `buf`.
+    pub fn dump(&self, base_addr: u64, buf: &mut Vec<u8>) -> Result<(), LinkError> {
+        // offset of the end of the current instruction
+        let mut end = 0i64;
         for inst in self.buf.borrow().iter() {
+            end += inst.len() as i64;
             match inst {
                 InstUnit::Inst(i) => {
                     buf.extend(i.iter());
                 }
                 InstUnit::JumpInst(j) => {
-                    let obj = self
-                        .label_buf
-                        .borrow()
-                        .get(&j.label)
-                        .cloned()
-                        .ok_or_else(|| LinkError(j.label.clone()))?;
-                    let obj = base_addr + obj;
+                    let obj = self.label_offset(&j.label)?;
+                    let out_of_range = || LinkError::OutOfRange(j.label.clone());
+                    let rel = obj as i64 - end;
+                    let field = match j.modify_range.1 - j.modify_range.0 {
+                        1 => i8::try_from(rel)
+                            .map_err(|_| out_of_range())?
+                            .to_le_bytes()
+                            .to_vec(),
+                        2 => i16::try_from(rel)
+                            .map_err(|_| out_of_range())?
+                            .to_le_bytes()
+                            .to_vec(),
+                        4 => i32::try_from(rel)
+                            .map_err(|_| out_of_range())?
+                            .to_le_bytes()
+                            .to_vec(),
+                        _ => base_addr
+                            .checked_add(obj as u64)
+                            .ok_or_else(out_of_range)?
+                            .to_le_bytes()
+                            .to_vec(),
+                    };
                     buf.extend(j.opcodes[..j.modify_range.0].iter());
-                    buf.extend(obj.to_ne_bytes());
+                    buf.extend(field);
                     buf.extend(j.opcodes[j.modify_range.1..].iter());
+                }
+                InstUnit::RipInst(r) => {
+                    let obj = self.label_offset(&r.label)?;
+                    // relative, the base address cancels out
+                    let disp = i32::try_from(obj as i64 - end)
+                        .map_err(|_| LinkError::OutOfRange(r.label.clone()))?;
+                    buf.extend(r.opcodes[..r.modify_range.0].iter());
+                    buf.extend(disp.to_le_bytes());
+                    buf.extend(r.opcodes[r.modify_range.1..].iter());
                 } /*
                   InstUnit::CallInst(j) => {
                       let j = &j.0;
@@ -133,3 +275,24 @@ impl InstBuffer {
         Ok(())
     }
 }
+
+/// Encoders write straight into the buffer, consecutive bytes are kept in one `InstUnit::Inst`.
+impl CodeSink for InstBuffer {
+    fn put_byte(&mut self, byte: u8) {
+        self.put_bytes(&[byte])
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        *self.offset.get_mut() += bytes.len() as u32;
+        let buf = self.buf.get_mut();
+        if let Some(InstUnit::Inst(last)) = buf.last_mut() {
+            last.extend_from_slice(bytes);
+        } else {
+            buf.push(InstUnit::Inst(bytes.to_vec()));
+        }
+    }
+
+    fn position(&self) -> usize {
+        *self.offset.borrow() as usize
+    }
+}
@@ -1,140 +1,233 @@
-use crate::insts::x86_64::{
-    inst,
-    registers::{RegisterXmm, ScaledIndex, TargetReg},
-    sse_inst, Imm, Op1,
+use crate::insts::{
+    x86_64::{
+        digit_inst_to, extend_bit8_inst_to, inst_to, plus_reg_inst_to,
+        registers::{GpReg, RegisterXmm, ScaledIndex, TargetReg},
+        sized_inst_to, sse_inst_to, EncodeError, Imm, Op1, OperandSize,
+    },
+    CodeSink,
 };
 
 use super::ImmByte;
 
+/// `name = name_to(args)` defines `name(args) -> Vec<u8>` on top of the sink encoder `name_to`.
+macro_rules! vec_encoder {
+    ($name:ident = $to:ident($($arg:ident: $ty:ty),*)) => {
+        #[doc = concat!("`", stringify!($to), "` into a new `Vec<u8>`, panics on an `EncodeError`.")]
+        pub fn $name($($arg: $ty),*) -> Vec<u8> {
+            let mut buf = Vec::new();
+            if let Err(e) = $to(&mut buf, $($arg),*) {
+                panic!("{}: {}", stringify!($name), e);
+            }
+            buf
+        }
+    };
+}
+
 /// ## mov
 /// - mov
 /// mov op1(reg/mem) into op2(reg)
-pub fn mov(is_atomic: bool, is_long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(is_atomic, is_long_mode, &[0x89], Some(op1), Some(op2), None)
-}
-
-pub fn mov_zero_extend_bit8(
+pub fn mov_to(
+    sink: &mut impl CodeSink,
     is_atomic: bool,
-    is_long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
-) -> Vec<u8> {
-    inst(
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         is_atomic,
-        is_long_mode,
-        &[0x0f, 0xb6],
+        size,
+        size.opcode(&[0x88], &[0x89]),
         Some(op1),
-        Some(op2),
+        Some(op2.into()),
         None,
     )
 }
+vec_encoder!(mov = mov_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn mov_zero_extend_bit16(
+pub fn mov_zero_extend_bit8_to(
+    sink: &mut impl CodeSink,
+    is_atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    extend_bit8_inst_to(sink, is_atomic, size, &[0x0f, 0xb6], op1, op2.into())
+}
+vec_encoder!(mov_zero_extend_bit8 = mov_zero_extend_bit8_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
+
+pub fn mov_zero_extend_bit16_to(
+    sink: &mut impl CodeSink,
     is_atomic: bool,
-    is_long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
-) -> Vec<u8> {
-    inst(
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
         is_atomic,
-        is_long_mode,
+        size,
         &[0x0f, 0xb7],
         Some(op1),
-        Some(op2),
+        Some(op2.into()),
         None,
     )
 }
+vec_encoder!(mov_zero_extend_bit16 = mov_zero_extend_bit16_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn mov_sign_extend_bit8(
+pub fn mov_sign_extend_bit8_to(
+    sink: &mut impl CodeSink,
+    is_atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    extend_bit8_inst_to(sink, is_atomic, size, &[0x0f, 0xbe], op1, op2.into())
+}
+vec_encoder!(mov_sign_extend_bit8 = mov_sign_extend_bit8_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
+
+pub fn mov_sign_extend_bit16_to(
+    sink: &mut impl CodeSink,
     is_atomic: bool,
-    is_long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
-) -> Vec<u8> {
-    inst(
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
         is_atomic,
-        is_long_mode,
-        &[0x0f, 0xbe],
+        size,
+        &[0x0f, 0xbf],
         Some(op1),
-        Some(op2),
+        Some(op2.into()),
         None,
     )
 }
+vec_encoder!(mov_sign_extend_bit16 = mov_sign_extend_bit16_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn mov_sign_extend_bit16(
+pub fn mov_sign_extend_bit32_to(
+    sink: &mut impl CodeSink,
     is_atomic: bool,
-    is_long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
-) -> Vec<u8> {
-    inst(
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
         is_atomic,
-        is_long_mode,
-        &[0x0f, 0xbf],
+        size,
+        &[0x63],
         Some(op1),
-        Some(op2),
+        Some(op2.into()),
         None,
     )
 }
-
-pub fn mov_sign_extend_bit32(
-    is_atomic: bool,
-    is_long_mode: bool,
-    op1: Op1,
-    op2: TargetReg,
-) -> Vec<u8> {
-    inst(is_atomic, is_long_mode, &[0x63], Some(op1), Some(op2), None)
-}
+vec_encoder!(mov_sign_extend_bit32 = mov_sign_extend_bit32_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - mov_rev
 /// mov_rev is the same as mov, but the source and destination operands are reversed.
-pub fn mov_rev(is_atomic: bool, is_long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(is_atomic, is_long_mode, &[0x8b], Some(op1), Some(op2), None)
-}
-
-/// - mov_imm[16/32/64(long_mode)]_into_reg
-pub fn mov_imm_into_reg(is_atomic: bool, is_long_mode: bool, op1: TargetReg, op2: u64) -> Vec<u8> {
-    let imm_byte = if is_long_mode {
-        ImmByte::Bit64
-    } else {
-        ImmByte::Bit32
-    };
-    inst(
+pub fn mov_rev_to(
+    sink: &mut impl CodeSink,
+    is_atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         is_atomic,
-        is_long_mode,
-        &[0xb8],
-        Some(Op1::Direct(op1)),
+        size,
+        size.opcode(&[0x8a], &[0x8b]),
+        Some(op1),
+        Some(op2.into()),
         None,
-        Some(Imm(op2, imm_byte)),
     )
 }
+vec_encoder!(mov_rev = mov_rev_to(is_atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn imm_sign_extend_into_reg(
+/// - mov_imm_into_reg: imm8/16/32 by size, imm64 at Bit64
+pub fn mov_imm_into_reg_to(
+    sink: &mut impl CodeSink,
     is_atomic: bool,
-    is_long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: TargetReg,
     op2: u64,
-) -> Vec<u8> {
-    inst(
-        is_atomic,
-        is_long_mode,
-        &[0x7c],
-        Some(Op1::Direct(op1)),
-        None,
-        Some(Imm(op2, ImmByte::Bit32)),
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    let imm = if size == OperandSize::Bit64 {
+        Imm(op2, ImmByte::Bit64)
+    } else {
+        let imm = u32::try_from(op2).map_err(|_| EncodeError::ImmOutOfRange { imm: op2, size })?;
+        size.imm(imm)?
+    };
+    // B0+r / B8+r, the register is in the opcode
+    let opcode = if size == OperandSize::Bit8 {
+        0xb0
+    } else {
+        0xb8
+    };
+    plus_reg_inst_to(sink, is_atomic, size, opcode, op1, Some(imm))
+}
+vec_encoder!(mov_imm_into_reg = mov_imm_into_reg_to(is_atomic: bool, size: impl Into<OperandSize>, op1: TargetReg, op2: u64));
+
+pub fn imm_sign_extend_into_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xc6], &[0xc7]),
+        0,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(imm_sign_extend_into_reg = imm_sign_extend_into_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
 ///  movs == movsq
-pub fn movs(atomic: bool) -> Vec<u8> {
-    inst(atomic, false, &[0xa5], None, None, None)
+pub fn movs_to(sink: &mut impl CodeSink, atomic: bool) -> Result<(), EncodeError> {
+    inst_to(sink, atomic, false, &[0xa5], None, None, None)
 }
+vec_encoder!(movs = movs_to(atomic: bool));
 
 /// ## push
 ///
 /// - push reg
-pub fn push_reg(atomic: bool, reg: TargetReg) -> Vec<u8> {
-    inst(
+pub fn push_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    reg: TargetReg,
+) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
         atomic,
         false,
         &[0x60],
@@ -143,10 +236,12 @@ pub fn push_reg(atomic: bool, reg: TargetReg) -> Vec<u8> {
         Some(Imm(reg as u64, ImmByte::Bit8)),
     )
 }
+vec_encoder!(push_reg = push_reg_to(atomic: bool, reg: TargetReg));
 
 /// - push_imm
-pub fn push_imm(atomic: bool, imm: u32) -> Vec<u8> {
-    inst(
+pub fn push_imm_to(sink: &mut impl CodeSink, atomic: bool, imm: u32) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
         atomic,
         false,
         &[0x60],
@@ -155,64 +250,158 @@ pub fn push_imm(atomic: bool, imm: u32) -> Vec<u8> {
         Some(Imm(imm as u64, ImmByte::Bit32)),
     )
 }
+vec_encoder!(push_imm = push_imm_to(atomic: bool, imm: u32));
 
 /// - push_all
-pub fn push_all(atomic: bool) -> Vec<u8> {
-    inst(atomic, false, &[0x60], None, None, None)
+pub fn push_all_to(sink: &mut impl CodeSink, atomic: bool) -> Result<(), EncodeError> {
+    inst_to(sink, atomic, false, &[0x60], None, None, None)
 }
+vec_encoder!(push_all = push_all_to(atomic: bool));
 
 /// ## add
 /// - add_to_eax(rax)
 
-pub fn add_first_reg(atomic: bool, long_mode: bool, imm: u32) -> Vec<u8> {
-    inst(
+pub fn add_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         atomic,
-        long_mode,
-        &[0x05],
+        size,
+        size.opcode(&[0x04], &[0x05]),
         None,
         None,
-        Some(Imm(imm as u64, ImmByte::Bit32)),
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(add_first_reg = add_first_reg_to(atomic: bool, size: impl Into<OperandSize>, imm: u32));
 
-pub fn add_imm32(atomic: bool, long_mode: bool, op1: Op1, imm: u32) -> Vec<u8> {
-    inst(
+pub fn add_imm32_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         atomic,
-        long_mode,
-        &[0x81],
+        size,
+        size.opcode(&[0x80], &[0x81]),
         Some(op1),
         None,
-        Some(Imm(imm as u64, ImmByte::Bit32)),
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(add_imm32 = add_imm32_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
-pub fn add_imm8(atomic: bool, long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+pub fn add_imm8_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u8,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         atomic,
-        long_mode,
-        &[0x83],
+        size,
+        size.opcode(&[0x80], &[0x83]),
         Some(op1),
         None,
         Some(Imm(imm as u64, ImmByte::Bit8)),
     )
 }
+vec_encoder!(add_imm8 = add_imm8_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, imm: u8));
 
-pub fn add(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x01], Some(op1), Some(op2), None)
+pub fn add_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0x00], &[0x01]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(add = add_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - add_rev: add_rev is the same as add, but the source and destination operands are reversed.
-pub fn add_rev(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x03], Some(op1), Some(op2), None)
+pub fn add_rev_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0x02], &[0x03]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(add_rev = add_rev_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn lea(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x8d], Some(op1), Some(op2), None)
+pub fn lea_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        &[0x8d],
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(lea = lea_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn inc(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xfe], Some(op1), None, None)
+pub fn inc_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xfe], &[0xff]),
+        Some(op1),
+        None,
+        None,
+    )
 }
+vec_encoder!(inc = inc_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
 // pub fn inc_reg32(atomic: bool, op1: Register32) -> Vec<u8> {
 //     inst(atomic, false, &[0x40], None, None, Some(Imm::from(op1)))
@@ -220,47 +409,127 @@ pub fn inc(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
 
 /// ## sub
 
-pub fn sub_first_reg(atomic: bool, imm: u32) -> Vec<u8> {
-    inst(atomic, false, &[0x2d], None, None, Some(Imm::from(imm)))
+pub fn sub_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0x2c], &[0x2d]),
+        None,
+        None,
+        Some(size.imm(imm)?),
+    )
 }
+vec_encoder!(sub_first_reg = sub_first_reg_to(atomic: bool, size: impl Into<OperandSize>, imm: u32));
 
-pub fn sub_imm(atomic: bool, long_mode: bool, op1: Op1, imm: u32) -> Vec<u8> {
-    inst(
+pub fn sub_imm_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         atomic,
-        long_mode,
-        &[0x81, 5],
-        Some(op1),
-        None,
-        Some(Imm::from(imm)),
+        size,
+        size.opcode(&[0x80], &[0x81]),
+        5,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(sub_imm = sub_imm_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
-pub fn sub_signed_imm8(atomic: bool, long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+pub fn sub_signed_imm8_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u8,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         atomic,
-        long_mode,
-        &[0x83, 5],
-        Some(op1),
-        None,
+        size,
+        size.opcode(&[0x80], &[0x83]),
+        5,
+        op1,
         Some(Imm::from(imm)),
     )
 }
+vec_encoder!(sub_signed_imm8 = sub_signed_imm8_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, imm: u8));
 
 /// - sub: Subtract r32 from r/m32
-pub fn sub(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x29], Some(op1), Some(op2), None)
+pub fn sub_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0x28], &[0x29]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(sub = sub_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - sub_rev: Subtract r/m32 from r32
-pub fn sub_rev(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x2b], Some(op1), Some(op2), None)
+pub fn sub_rev_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0x2a], &[0x2b]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(sub_rev = sub_rev_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 // todo: sbb
 
-pub fn dec(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xff, 1], Some(op1), None, None)
+pub fn dec_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xfe], &[0xff]),
+        1,
+        op1,
+        None,
+    )
 }
+vec_encoder!(dec = dec_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
 // pub fn dec_reg32(atomic: bool, reg: Register32) -> Vec<u8> {
 //     inst(atomic, false, &[0x48], None, None, Some(Imm::from(reg)))
@@ -268,228 +537,488 @@ pub fn dec(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
 
 /// neg
 
-pub fn neg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf6], Some(op1), None, None)
+pub fn neg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        3,
+        op1,
+        None,
+    )
 }
+vec_encoder!(neg = neg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
 /// ## mul
 
-pub fn mul_byte_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf6, 4], Some(op1), None, None)
+pub fn mul_byte_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    mul_first_reg_to(sink, atomic, OperandSize::Bit8, op1)
 }
+vec_encoder!(mul_byte_first_reg = mul_byte_first_reg_to(atomic: bool, op1: Op1));
 
-pub fn mul_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf7, 4], Some(op1), None, None)
+pub fn mul_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        4,
+        op1,
+        None,
+    )
 }
+vec_encoder!(mul_first_reg = mul_first_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
-pub fn imul_byte_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf6, 5], Some(op1), None, None)
+pub fn imul_byte_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    imul_first_reg_to(sink, atomic, OperandSize::Bit8, op1)
 }
+vec_encoder!(imul_byte_first_reg = imul_byte_first_reg_to(atomic: bool, op1: Op1));
 
-pub fn imul_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf7, 5], Some(op1), None, None)
+pub fn imul_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        5,
+        op1,
+        None,
+    )
 }
+vec_encoder!(imul_first_reg = imul_first_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
-pub fn imul_reg(atomic: bool, long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(atomic, long_mode, &[0x0f, 0xaf], Some(op1), Some(op2), None)
+pub fn imul_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
+        atomic,
+        size,
+        &[0x0f, 0xaf],
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(imul_reg = imul_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
-pub fn imul_reg_and_imm8(
+pub fn imul_reg_and_imm8_to(
+    sink: &mut impl CodeSink,
     atomic: bool,
-    long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
+    op2: impl Into<GpReg>,
     imm: u8,
-) -> Vec<u8> {
-    inst(
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
         atomic,
-        long_mode,
+        size,
         &[0x6b],
         Some(op1),
-        Some(op2),
+        Some(op2.into()),
         Some(Imm::from(imm)),
     )
 }
+vec_encoder!(imul_reg_and_imm8 = imul_reg_and_imm8_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>, imm: u8));
 
-pub fn imul_reg_and_imm32(
+pub fn imul_reg_and_imm32_to(
+    sink: &mut impl CodeSink,
     atomic: bool,
-    long_mode: bool,
+    size: impl Into<OperandSize>,
     op1: Op1,
-    op2: TargetReg,
+    op2: impl Into<GpReg>,
     imm: u32,
-) -> Vec<u8> {
-    inst(
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(
+        sink,
         atomic,
-        long_mode,
+        size,
         &[0x69],
         Some(op1),
-        Some(op2),
-        Some(Imm::from(imm)),
+        Some(op2.into()),
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(imul_reg_and_imm32 = imul_reg_and_imm32_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>, imm: u32));
 
 /// ## div
 
-pub fn div_byte_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf6, 6], Some(op1), None, None)
+pub fn div_byte_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    div_first_reg_to(sink, atomic, OperandSize::Bit8, op1)
 }
+vec_encoder!(div_byte_first_reg = div_byte_first_reg_to(atomic: bool, op1: Op1));
 
-pub fn div_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf7, 6], Some(op1), None, None)
+pub fn div_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        6,
+        op1,
+        None,
+    )
 }
+vec_encoder!(div_first_reg = div_first_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
-pub fn idiv_byte_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf6, 6], Some(op1), None, None)
+pub fn idiv_byte_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    idiv_first_reg_to(sink, atomic, OperandSize::Bit8, op1)
 }
+vec_encoder!(idiv_byte_first_reg = idiv_byte_first_reg_to(atomic: bool, op1: Op1));
 
-pub fn idiv_first_reg(atomic: bool, long_mode: bool, op1: Op1) -> Vec<u8> {
-    inst(atomic, long_mode, &[0xf7, 6], Some(op1), None, None)
+pub fn idiv_first_reg_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        atomic,
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        7,
+        op1,
+        None,
+    )
 }
+vec_encoder!(idiv_first_reg = idiv_first_reg_to(atomic: bool, size: impl Into<OperandSize>, op1: Op1));
 
 /// cbw
 
+/// AX ← sign-extend of AL (Bit16).
 /// EAX ← sign-extend of AX.
 /// RAX ← sign-extend of EAX(long mode only).
-pub fn sign_extend(long_mode: bool) -> Vec<u8> {
-    inst(false, long_mode, &[0x98], None, None, None)
-}
-
+pub fn sign_extend_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(sink, false, size, &[0x98], None, None, None)
+}
+vec_encoder!(sign_extend = sign_extend_to(size: impl Into<OperandSize>));
+
+/// DX:AX ← sign-extend of AX (Bit16)
 /// EDX:EAX ← sign-extend of EAX
 /// RDX:RAX ← sign-extend of RAX(long mode only).
-pub fn sign_extend2(long_mode: bool) -> Vec<u8> {
-    inst(false, long_mode, &[0x98], None, None, None)
-}
+pub fn sign_extend2_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    if size == OperandSize::Bit8 {
+        return Err(EncodeError::UnsupportedSize { size });
+    }
+    sized_inst_to(sink, false, size, &[0x99], None, None, None)
+}
+vec_encoder!(sign_extend2 = sign_extend2_to(size: impl Into<OperandSize>));
 
 /// ## cmp
 
-pub fn cmp_first_reg_and_imm(long_mode: bool, imm: u32) -> Vec<u8> {
-    inst(false, long_mode, &[0x3d], None, None, Some(Imm::from(imm)))
+pub fn cmp_first_reg_and_imm_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x3c], &[0x3d]),
+        None,
+        None,
+        Some(size.imm(imm)?),
+    )
 }
+vec_encoder!(cmp_first_reg_and_imm = cmp_first_reg_and_imm_to(size: impl Into<OperandSize>, imm: u32));
 
-/// - cmp: Compare imm32 [with r/m32 | sign-extended to 64-bits with r/m64]
-pub fn cmp_imm(long_mode: bool, op1: Op1, imm: u32) -> Vec<u8> {
-    inst(
+/// - cmp_imm: Compare imm8/16/32 by size [with r/m | sign-extended to 64-bits with r/m64]
+pub fn cmp_imm_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x81, 7],
-        Some(op1),
-        None,
-        Some(Imm(imm as u64, ImmByte::Bit32)),
+        size,
+        size.opcode(&[0x80], &[0x81]),
+        7,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(cmp_imm = cmp_imm_to(size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
-/// - cmp_imm8: Compare imm8 with r/m8
-pub fn cmp_imm8(long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+/// - cmp_imm8: Compare imm8 [with r/m8 | sign-extended with r/m16/32/64]
+pub fn cmp_imm8_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u8,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x83, 7],
-        Some(op1),
-        None,
-        Some(Imm(imm as u64, ImmByte::Bit8)),
+        size,
+        size.opcode(&[0x80], &[0x83]),
+        7,
+        op1,
+        Some(Imm::from(imm)),
     )
 }
+vec_encoder!(cmp_imm8 = cmp_imm8_to(size: impl Into<OperandSize>, op1: Op1, imm: u8));
 
 /// - cmp: Compare r32 with r/m32(64)
-pub fn cmp(long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x39, 7], Some(op1), Some(op2), None)
+pub fn cmp_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x38], &[0x39]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(cmp = cmp_to(size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - cmp_rev: Compare r/m32(64) with r32
-pub fn cmp_rev(long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x3b, 7], Some(op1), Some(op2), None)
+pub fn cmp_rev_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x3a], &[0x3b]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(cmp_rev = cmp_rev_to(size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - cmps: Compares quadword at address (R|E)SI with quadword at address (R|E)DI and sets the status flags accordingly.
-pub fn cmps(long_mode: bool) -> Vec<u8> {
-    inst(false, long_mode, &[0xa7], None, None, None)
-}
-
-/// - test_first_reg
-
-pub fn test_first_reg_and_imm8(imm: u8) -> Vec<u8> {
-    inst(
+pub fn cmps_to(sink: &mut impl CodeSink, size: impl Into<OperandSize>) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         false,
-        false,
-        &[0xa8],
+        size,
+        size.opcode(&[0xa6], &[0xa7]),
+        None,
         None,
         None,
-        Some(Imm(imm as u64, ImmByte::Bit8)),
     )
 }
+vec_encoder!(cmps = cmps_to(size: impl Into<OperandSize>));
 
-pub fn test_first_reg(long_mode: bool, imm: Imm) -> Vec<u8> {
-    inst(false, long_mode, &[0xa9], None, None, Some(imm))
+/// - test_first_reg
+
+pub fn test_first_reg_and_imm8_to(sink: &mut impl CodeSink, imm: u8) -> Result<(), EncodeError> {
+    test_first_reg_to(sink, OperandSize::Bit8, imm as u32)
 }
+vec_encoder!(test_first_reg_and_imm8 = test_first_reg_and_imm8_to(imm: u8));
 
-pub fn test_imm8(long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+pub fn test_first_reg_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0xf6, 0],
-        Some(op1),
+        size,
+        size.opcode(&[0xa8], &[0xa9]),
         None,
-        Some(Imm(imm as u64, ImmByte::Bit8)),
+        None,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(test_first_reg = test_first_reg_to(size: impl Into<OperandSize>, imm: u32));
+
+pub fn test_imm8_to(sink: &mut impl CodeSink, op1: Op1, imm: u8) -> Result<(), EncodeError> {
+    test_imm_to(sink, OperandSize::Bit8, op1, imm as u32)
+}
+vec_encoder!(test_imm8 = test_imm8_to(op1: Op1, imm: u8));
 
-/// - test_imm: Test imm32 [with r/m32 | sign-extended to 64-bits with r/m64]
-pub fn test_imm(long_mode: bool, op1: Op1, op2: TargetReg, imm: u32) -> Vec<u8> {
-    inst(
+/// - test_imm: Test imm8/16/32 by size [with r/m | sign-extended to 64-bits with r/m64]
+pub fn test_imm_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0xf7, 0],
-        Some(op1),
-        Some(op2),
-        Some(Imm(imm as u64, ImmByte::Bit32)),
+        size,
+        size.opcode(&[0xf6], &[0xf7]),
+        0,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(test_imm = test_imm_to(size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
 // - test_u8
-pub fn test_u8(long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x84], Some(op1), Some(op2), None)
+pub fn test_u8_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    test_to(sink, OperandSize::Bit8, op1, op2)
 }
+vec_encoder!(test_u8 = test_u8_to(op1: Op1, op2: impl Into<GpReg>));
 
 /// - test
-pub fn test(long_mode: bool, op1: Op1, op2: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x85], Some(op1), Some(op2), None)
+pub fn test_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    op2: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x84], &[0x85]),
+        Some(op1),
+        Some(op2.into()),
+        None,
+    )
 }
+vec_encoder!(test = test_to(size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
 
 /// - int1
 #[inline]
-pub fn int1() -> Vec<u8> {
-    inst(false, false, &[0xf1], None, None, None)
+pub fn int1_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xf1], None, None, None)
 }
+vec_encoder!(int1 = int1_to());
 
 /// - int3
 #[inline]
-pub fn int3() -> Vec<u8> {
-    inst(false, false, &[0xcc], None, None, None)
+pub fn int3_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xcc], None, None, None)
 }
+vec_encoder!(int3 = int3_to());
 
 /// - int
 #[inline]
-pub fn int(imm: u8) -> Vec<u8> {
-    inst(false, false, &[0xcd], None, None, Some(Imm::from(imm)))
+pub fn int_to(sink: &mut impl CodeSink, imm: u8) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
+        false,
+        false,
+        &[0xcd],
+        None,
+        None,
+        Some(Imm::from(imm)),
+    )
 }
+vec_encoder!(int = int_to(imm: u8));
 
 /// - into
 #[inline]
-pub fn into() -> Vec<u8> {
-    inst(false, false, &[0xce], None, None, None)
+pub fn into_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xce], None, None, None)
 }
+vec_encoder!(into = into_to());
 
 /// - syscall
 #[inline]
-pub fn syscall() -> Vec<u8> {
-    inst(false, false, &[0x0f, 0x05], None, None, None)
+pub fn syscall_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0x0f, 0x05], None, None, None)
 }
+vec_encoder!(syscall = syscall_to());
 
 /// - sysenter
 #[inline]
-pub fn sysenter() -> Vec<u8> {
-    inst(false, false, &[0x0f, 0x34], None, None, None)
+pub fn sysenter_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0x0f, 0x34], None, None, None)
 }
+vec_encoder!(sysenter = sysenter_to());
 
 /// - call
 
@@ -506,13 +1035,15 @@ pub fn sysenter() -> Vec<u8> {
 // }
 
 /// jit use it
-pub fn call_addr_literal(addr: Imm) -> Vec<u8> {
-    inst(false, false, &[0x9a], None, None, Some(addr))
+pub fn call_addr_literal_to(sink: &mut impl CodeSink, addr: Imm) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0x9a], None, None, Some(addr))
 }
+vec_encoder!(call_addr_literal = call_addr_literal_to(addr: Imm));
 
-pub fn call_reg(op1: Op1) -> Vec<u8> {
-    inst(false, false, &[0x9a], Some(op1), None, None)
+pub fn call_reg_to(sink: &mut impl CodeSink, op1: Op1) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0x9a], Some(op1), None, None)
 }
+vec_encoder!(call_reg = call_reg_to(op1: Op1));
 
 /// ## jmp
 
@@ -529,9 +1060,10 @@ pub fn call_reg(op1: Op1) -> Vec<u8> {
 // }
 
 /// jit use it
-pub fn jmp_addr_literal(addr: Imm) -> Vec<u8> {
-    inst(false, false, &[0xea], None, None, Some(addr))
+pub fn jmp_addr_literal_to(sink: &mut impl CodeSink, addr: Imm) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xea], None, None, Some(addr))
 }
+vec_encoder!(jmp_addr_literal = jmp_addr_literal_to(addr: Imm));
 
 /// runtime_symbol only
 // pub fn jmp_to_runtime_symbol(label: String) -> JumpInst {
@@ -546,16 +1078,26 @@ pub fn jmp_addr_literal(addr: Imm) -> Vec<u8> {
 //     JumpInst::from(opcodes, ImmByte::Bit32, label)
 // }
 
-pub fn jmp_to_reg(reg: TargetReg) -> Vec<u8> {
-    inst(false, false, &[0xff, 4], Some(Op1::Direct(reg)), None, None)
+pub fn jmp_to_reg_to(sink: &mut impl CodeSink, reg: TargetReg) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
+        false,
+        false,
+        &[0xff, 4],
+        Some(Op1::Direct(reg)),
+        None,
+        None,
+    )
 }
+vec_encoder!(jmp_to_reg = jmp_to_reg_to(reg: TargetReg));
 
 /// ## conditional jump
 
 macro_rules! impl_cond_jump_inst {
-    ($name:ident, $expr: expr) => {
-        pub fn $name(addr: u64) -> Vec<u8> {
-            inst(
+    ($name:ident, $to:ident, $expr: expr) => {
+        pub fn $to(sink: &mut impl CodeSink, addr: u64) -> Result<(), EncodeError> {
+            inst_to(
+                sink,
                 false,
                 false,
                 $expr,
@@ -564,158 +1106,399 @@ macro_rules! impl_cond_jump_inst {
                 Some(Imm(addr, ImmByte::Bit64)),
             )
         }
+        vec_encoder!($name = $to(addr: u64));
     };
 }
 
-impl_cond_jump_inst!(ja, &[0x0f, 0x87]);
-impl_cond_jump_inst!(jb, &[0x0f, 0x82]);
-impl_cond_jump_inst!(jc, &[0x0f, 0x82]);
-impl_cond_jump_inst!(je, &[0x0f, 0x84]);
-impl_cond_jump_inst!(jg, &[0x0f, 0x8f]);
-impl_cond_jump_inst!(jl, &[0x0f, 0x8c]);
-impl_cond_jump_inst!(jo, &[0x0f, 0x80]);
-impl_cond_jump_inst!(jp, &[0x0f, 0x8a]);
-impl_cond_jump_inst!(js, &[0x0f, 0x88]);
-impl_cond_jump_inst!(jz, &[0x0f, 0x84]);
-impl_cond_jump_inst!(jae, &[0x0f, 0x83]);
-impl_cond_jump_inst!(jbe, &[0x0f, 0x86]);
-impl_cond_jump_inst!(jge, &[0x0f, 0x8d]);
-impl_cond_jump_inst!(jle, &[0x0f, 0x8e]);
-impl_cond_jump_inst!(jpe, &[0x0f, 0x8a]);
-impl_cond_jump_inst!(jpo, &[0x0f, 0x8b]);
-impl_cond_jump_inst!(jna, &[0x0f, 0x86]);
-impl_cond_jump_inst!(jnb, &[0x0f, 0x83]);
-impl_cond_jump_inst!(jnc, &[0x0f, 0x83]);
-impl_cond_jump_inst!(jne, &[0x0f, 0x85]);
-impl_cond_jump_inst!(jng, &[0x0f, 0x8e]);
-impl_cond_jump_inst!(jnl, &[0x0f, 0x8d]);
-impl_cond_jump_inst!(jno, &[0x0f, 0x81]);
-impl_cond_jump_inst!(jnp, &[0x0f, 0x8b]);
-impl_cond_jump_inst!(jns, &[0x0f, 0x89]);
-impl_cond_jump_inst!(jnz, &[0x0f, 0x85]);
-impl_cond_jump_inst!(jnae, &[0x0f, 0x82]);
-impl_cond_jump_inst!(jnbe, &[0x0f, 0x87]);
-impl_cond_jump_inst!(jnge, &[0x0f, 0x8c]);
-impl_cond_jump_inst!(jnle, &[0x0f, 0x8f]);
+impl_cond_jump_inst!(ja, ja_to, &[0x0f, 0x87]);
+impl_cond_jump_inst!(jb, jb_to, &[0x0f, 0x82]);
+impl_cond_jump_inst!(jc, jc_to, &[0x0f, 0x82]);
+impl_cond_jump_inst!(je, je_to, &[0x0f, 0x84]);
+impl_cond_jump_inst!(jg, jg_to, &[0x0f, 0x8f]);
+impl_cond_jump_inst!(jl, jl_to, &[0x0f, 0x8c]);
+impl_cond_jump_inst!(jo, jo_to, &[0x0f, 0x80]);
+impl_cond_jump_inst!(jp, jp_to, &[0x0f, 0x8a]);
+impl_cond_jump_inst!(js, js_to, &[0x0f, 0x88]);
+impl_cond_jump_inst!(jz, jz_to, &[0x0f, 0x84]);
+impl_cond_jump_inst!(jae, jae_to, &[0x0f, 0x83]);
+impl_cond_jump_inst!(jbe, jbe_to, &[0x0f, 0x86]);
+impl_cond_jump_inst!(jge, jge_to, &[0x0f, 0x8d]);
+impl_cond_jump_inst!(jle, jle_to, &[0x0f, 0x8e]);
+impl_cond_jump_inst!(jpe, jpe_to, &[0x0f, 0x8a]);
+impl_cond_jump_inst!(jpo, jpo_to, &[0x0f, 0x8b]);
+impl_cond_jump_inst!(jna, jna_to, &[0x0f, 0x86]);
+impl_cond_jump_inst!(jnb, jnb_to, &[0x0f, 0x83]);
+impl_cond_jump_inst!(jnc, jnc_to, &[0x0f, 0x83]);
+impl_cond_jump_inst!(jne, jne_to, &[0x0f, 0x85]);
+impl_cond_jump_inst!(jng, jng_to, &[0x0f, 0x8e]);
+impl_cond_jump_inst!(jnl, jnl_to, &[0x0f, 0x8d]);
+impl_cond_jump_inst!(jno, jno_to, &[0x0f, 0x81]);
+impl_cond_jump_inst!(jnp, jnp_to, &[0x0f, 0x8b]);
+impl_cond_jump_inst!(jns, jns_to, &[0x0f, 0x89]);
+impl_cond_jump_inst!(jnz, jnz_to, &[0x0f, 0x85]);
+impl_cond_jump_inst!(jnae, jnae_to, &[0x0f, 0x82]);
+impl_cond_jump_inst!(jnbe, jnbe_to, &[0x0f, 0x87]);
+impl_cond_jump_inst!(jnge, jnge_to, &[0x0f, 0x8c]);
+impl_cond_jump_inst!(jnle, jnle_to, &[0x0f, 0x8f]);
 
 /// ## logic inst
 
 /// ### and
 
-pub fn and_first_reg_imm32(long_mode: bool, imm: u32) -> Vec<u8> {
-    inst(false, long_mode, &[0x25], None, None, Some(Imm::from(imm)))
+pub fn and_first_reg_imm32_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x24], &[0x25]),
+        None,
+        None,
+        Some(size.imm(imm)?),
+    )
 }
+vec_encoder!(and_first_reg_imm32 = and_first_reg_imm32_to(size: impl Into<OperandSize>, imm: u32));
 
-pub fn and_reg_imm32(long_mode: bool, op1: Op1, imm: u32) -> Vec<u8> {
-    inst(
+pub fn and_reg_imm32_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x81, 4],
-        Some(op1),
-        None,
-        Some(Imm::from(imm)),
+        size,
+        size.opcode(&[0x80], &[0x81]),
+        4,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(and_reg_imm32 = and_reg_imm32_to(size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
-pub fn and_reg_imm8(long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+pub fn and_reg_imm8_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u8,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x83, 4],
-        Some(op1),
-        None,
+        size,
+        size.opcode(&[0x80], &[0x83]),
+        4,
+        op1,
         Some(Imm::from(imm)),
     )
 }
+vec_encoder!(and_reg_imm8 = and_reg_imm8_to(size: impl Into<OperandSize>, op1: Op1, imm: u8));
 
-pub fn and(long_mode: bool, op1: Op1, reg: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x21], Some(op1), Some(reg), None)
+pub fn and_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    reg: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x20], &[0x21]),
+        Some(op1),
+        Some(reg.into()),
+        None,
+    )
 }
+vec_encoder!(and = and_to(size: impl Into<OperandSize>, op1: Op1, reg: impl Into<GpReg>));
 
-pub fn and_rev(long_mode: bool, op1: Op1, reg: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x23], Some(op1), Some(reg), None)
+pub fn and_rev_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    reg: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x22], &[0x23]),
+        Some(op1),
+        Some(reg.into()),
+        None,
+    )
 }
+vec_encoder!(and_rev = and_rev_to(size: impl Into<OperandSize>, op1: Op1, reg: impl Into<GpReg>));
 
 /// ### or
 
-pub fn or_first_reg_imm32(long_mode: bool, imm: u32) -> Vec<u8> {
-    inst(false, long_mode, &[0x0d], None, None, Some(Imm::from(imm)))
-}
-
-pub fn or_reg_imm32(long_mode: bool, op1: Op1, imm: u32) -> Vec<u8> {
-    inst(
+pub fn or_first_reg_imm32_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x81, 1],
-        Some(op1),
+        size,
+        size.opcode(&[0x0c], &[0x0d]),
         None,
-        Some(Imm::from(imm)),
+        None,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(or_first_reg_imm32 = or_first_reg_imm32_to(size: impl Into<OperandSize>, imm: u32));
 
-pub fn or_reg_imm8(long_mode: bool, op1: Op1, imm: u8) -> Vec<u8> {
-    inst(
+pub fn or_reg_imm32_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u32,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
         false,
-        long_mode,
-        &[0x83, 1],
-        Some(op1),
-        None,
-        Some(Imm::from(imm)),
+        size,
+        size.opcode(&[0x80], &[0x81]),
+        1,
+        op1,
+        Some(size.imm(imm)?),
     )
 }
+vec_encoder!(or_reg_imm32 = or_reg_imm32_to(size: impl Into<OperandSize>, op1: Op1, imm: u32));
 
-pub fn or(long_mode: bool, op1: Op1, reg: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x09], Some(op1), Some(reg), None)
+pub fn or_reg_imm8_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    imm: u8,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    digit_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x80], &[0x83]),
+        1,
+        op1,
+        Some(Imm::from(imm)),
+    )
 }
+vec_encoder!(or_reg_imm8 = or_reg_imm8_to(size: impl Into<OperandSize>, op1: Op1, imm: u8));
 
-pub fn or_rev(long_mode: bool, op1: Op1, reg: TargetReg) -> Vec<u8> {
-    inst(false, long_mode, &[0x0b], Some(op1), Some(reg), None)
+pub fn or_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    reg: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x08], &[0x09]),
+        Some(op1),
+        Some(reg.into()),
+        None,
+    )
 }
+vec_encoder!(or = or_to(size: impl Into<OperandSize>, op1: Op1, reg: impl Into<GpReg>));
 
-/// ## nop
-
-#[inline]
-pub fn nop() -> Vec<u8> {
-    nop1()
+pub fn or_rev_to(
+    sink: &mut impl CodeSink,
+    size: impl Into<OperandSize>,
+    op1: Op1,
+    reg: impl Into<GpReg>,
+) -> Result<(), EncodeError> {
+    let size = size.into();
+    sized_inst_to(
+        sink,
+        false,
+        size,
+        size.opcode(&[0x0a], &[0x0b]),
+        Some(op1),
+        Some(reg.into()),
+        None,
+    )
 }
+vec_encoder!(or_rev = or_rev_to(size: impl Into<OperandSize>, op1: Op1, reg: impl Into<GpReg>));
 
-pub fn nop1() -> Vec<u8> {
-    let r = inst(false, false, &[0x90], None, None, None);
-    debug_assert_eq!(dbg!(r.len()), 1);
-    r
-}
+// ## shift and rotate
+// `name` by 1, `name_cl` by CL, `name_imm8` by an immediate, the count is masked to 5 (6 at 64 bit) bits.
 
-pub fn nop2() -> Vec<u8> {
-    let r = inst(false, false, &[66, 0x90], None, None, None);
-    debug_assert_eq!(dbg!(r.len()), 2);
-    r
+macro_rules! impl_shift_inst {
+    ($name:ident, $to:ident, $cl:ident, $cl_to:ident, $imm8:ident, $imm8_to:ident, $digit:expr) => {
+        pub fn $to(
+            sink: &mut impl CodeSink,
+            size: impl Into<OperandSize>,
+            op1: Op1,
+        ) -> Result<(), EncodeError> {
+            let size = size.into();
+            digit_inst_to(sink, false, size, size.opcode(&[0xd0], &[0xd1]), $digit, op1, None)
+        }
+        vec_encoder!($name = $to(size: impl Into<OperandSize>, op1: Op1));
+
+        pub fn $cl_to(
+            sink: &mut impl CodeSink,
+            size: impl Into<OperandSize>,
+            op1: Op1,
+        ) -> Result<(), EncodeError> {
+            let size = size.into();
+            digit_inst_to(sink, false, size, size.opcode(&[0xd2], &[0xd3]), $digit, op1, None)
+        }
+        vec_encoder!($cl = $cl_to(size: impl Into<OperandSize>, op1: Op1));
+
+        pub fn $imm8_to(
+            sink: &mut impl CodeSink,
+            size: impl Into<OperandSize>,
+            op1: Op1,
+            imm: u8,
+        ) -> Result<(), EncodeError> {
+            let size = size.into();
+            digit_inst_to(
+                sink,
+                false,
+                size,
+                size.opcode(&[0xc0], &[0xc1]),
+                $digit,
+                op1,
+                Some(Imm::from(imm)),
+            )
+        }
+        vec_encoder!($imm8 = $imm8_to(size: impl Into<OperandSize>, op1: Op1, imm: u8));
+    };
 }
 
-pub fn nop3() -> Vec<u8> {
-    let r = nop_multi_reg(Op1::Direct(TargetReg::from(0)));
-    debug_assert_eq!(r.len(), 3);
-    r
+impl_shift_inst!(rol, rol_to, rol_cl, rol_cl_to, rol_imm8, rol_imm8_to, 0);
+impl_shift_inst!(ror, ror_to, ror_cl, ror_cl_to, ror_imm8, ror_imm8_to, 1);
+impl_shift_inst!(rcl, rcl_to, rcl_cl, rcl_cl_to, rcl_imm8, rcl_imm8_to, 2);
+impl_shift_inst!(rcr, rcr_to, rcr_cl, rcr_cl_to, rcr_imm8, rcr_imm8_to, 3);
+impl_shift_inst!(shl, shl_to, shl_cl, shl_cl_to, shl_imm8, shl_imm8_to, 4);
+impl_shift_inst!(shr, shr_to, shr_cl, shr_cl_to, shr_imm8, shr_imm8_to, 5);
+impl_shift_inst!(sar, sar_to, sar_cl, sar_cl_to, sar_imm8, sar_imm8_to, 7);
+
+// - shld / shrd
+// shift op1 by the count, filling in the bits of op2. 16/32/64 bit only.
+
+macro_rules! impl_double_shift_inst {
+    ($cl:ident, $cl_to:ident, $imm8:ident, $imm8_to:ident, $opcode:expr) => {
+        pub fn $cl_to(
+            sink: &mut impl CodeSink,
+            size: impl Into<OperandSize>,
+            op1: Op1,
+            op2: impl Into<GpReg>,
+        ) -> Result<(), EncodeError> {
+            let size = size.into();
+            if size == OperandSize::Bit8 {
+                return Err(EncodeError::UnsupportedSize { size });
+            }
+            sized_inst_to(
+                sink,
+                false,
+                size,
+                &[0x0f, $opcode + 1],
+                Some(op1),
+                Some(op2.into()),
+                None,
+            )
+        }
+        vec_encoder!($cl = $cl_to(size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>));
+
+        pub fn $imm8_to(
+            sink: &mut impl CodeSink,
+            size: impl Into<OperandSize>,
+            op1: Op1,
+            op2: impl Into<GpReg>,
+            imm: u8,
+        ) -> Result<(), EncodeError> {
+            let size = size.into();
+            if size == OperandSize::Bit8 {
+                return Err(EncodeError::UnsupportedSize { size });
+            }
+            sized_inst_to(
+                sink,
+                false,
+                size,
+                &[0x0f, $opcode],
+                Some(op1),
+                Some(op2.into()),
+                Some(Imm::from(imm)),
+            )
+        }
+        vec_encoder!($imm8 = $imm8_to(size: impl Into<OperandSize>, op1: Op1, op2: impl Into<GpReg>, imm: u8));
+    };
 }
 
-pub fn nop4() -> Vec<u8> {
-    let r = nop_multi_reg(Op1::DeRef(TargetReg::from(0), u8::MAX as usize));
-    debug_assert_eq!(r.len(), 4);
-    r
-}
+impl_double_shift_inst!(shld_cl, shld_cl_to, shld_imm8, shld_imm8_to, 0xa4);
+impl_double_shift_inst!(shrd_cl, shrd_cl_to, shrd_imm8, shrd_imm8_to, 0xac);
 
-pub fn nop5() -> Vec<u8> {
-    let r = nop_multi_reg(Op1::ScaleBase(
-        TargetReg::from(0),
-        TargetReg::from(0),
-        ScaledIndex::Id,
-        u8::MAX as usize,
-    ));
-    debug_assert_eq!(r.len(), 5);
-    r
-}
+/// ## nop
 
-pub fn nop6() -> Vec<u8> {
-    let r = inst(
+#[inline]
+pub fn nop_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    nop1_to(sink)
+}
+vec_encoder!(nop = nop_to());
+
+pub fn nop1_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    inst_to(sink, false, false, &[0x90], None, None, None)?;
+    debug_assert_eq!(sink.position() - start, 1);
+    Ok(())
+}
+vec_encoder!(nop1 = nop1_to());
+
+pub fn nop2_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    inst_to(sink, false, false, &[66, 0x90], None, None, None)?;
+    debug_assert_eq!(sink.position() - start, 2);
+    Ok(())
+}
+vec_encoder!(nop2 = nop2_to());
+
+pub fn nop3_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    nop_multi_reg_to(sink, Op1::Direct(TargetReg::from(0)))?;
+    debug_assert_eq!(sink.position() - start, 3);
+    Ok(())
+}
+vec_encoder!(nop3 = nop3_to());
+
+pub fn nop4_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    nop_multi_reg_to(sink, Op1::DeRef(TargetReg::from(0), -1))?;
+    debug_assert_eq!(sink.position() - start, 4);
+    Ok(())
+}
+vec_encoder!(nop4 = nop4_to());
+
+pub fn nop5_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    nop_multi_reg_to(
+        sink,
+        Op1::ScaleBase(TargetReg::from(0), TargetReg::from(0), ScaledIndex::Id, -1),
+    )?;
+    debug_assert_eq!(sink.position() - start, 5);
+    Ok(())
+}
+vec_encoder!(nop5 = nop5_to());
+
+pub fn nop6_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    inst_to(
+        sink,
         false,
         false,
         &[66, 0x0f, 0x1f],
@@ -723,34 +1506,44 @@ pub fn nop6() -> Vec<u8> {
             TargetReg::from(0),
             TargetReg::from(0),
             ScaledIndex::Id,
-            u8::MAX as usize,
+            -1,
         )),
         None,
         None,
-    );
-    debug_assert_eq!(dbg!(r.len()), 6);
-    r
-}
-
-pub fn nop7() -> Vec<u8> {
-    let r = nop_multi_reg(Op1::DeRef(TargetReg::from(0), u32::MAX as usize));
-    debug_assert_eq!(r.len(), 7);
-    r
+    )?;
+    debug_assert_eq!(sink.position() - start, 6);
+    Ok(())
 }
+vec_encoder!(nop6 = nop6_to());
 
-pub fn nop8() -> Vec<u8> {
-    let r = nop_multi_reg(Op1::ScaleBase(
-        TargetReg::from(0),
-        TargetReg::from(0),
-        ScaledIndex::Id,
-        u32::MAX as usize,
-    ));
-    debug_assert_eq!(r.len(), 8);
-    r
+pub fn nop7_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    nop_multi_reg_to(sink, Op1::DeRef(TargetReg::from(0), i32::MIN))?;
+    debug_assert_eq!(sink.position() - start, 7);
+    Ok(())
 }
+vec_encoder!(nop7 = nop7_to());
 
-pub fn nop9() -> Vec<u8> {
-    let r = inst(
+pub fn nop8_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    nop_multi_reg_to(
+        sink,
+        Op1::ScaleBase(
+            TargetReg::from(0),
+            TargetReg::from(0),
+            ScaledIndex::Id,
+            i32::MIN,
+        ),
+    )?;
+    debug_assert_eq!(sink.position() - start, 8);
+    Ok(())
+}
+vec_encoder!(nop8 = nop8_to());
+
+pub fn nop9_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    let start = sink.position();
+    inst_to(
+        sink,
         false,
         false,
         &[66, 0x0f, 0x1f],
@@ -758,31 +1551,36 @@ pub fn nop9() -> Vec<u8> {
             TargetReg::from(0),
             TargetReg::from(0),
             ScaledIndex::Id,
-            u32::MAX as usize,
+            i32::MIN,
         )),
         None,
         None,
-    );
-    debug_assert_eq!(dbg!(r.len()), 9);
-    r
+    )?;
+    debug_assert_eq!(sink.position() - start, 9);
+    Ok(())
 }
+vec_encoder!(nop9 = nop9_to());
 
-pub fn nop_multi_reg(op1: Op1) -> Vec<u8> {
-    inst(false, false, &[0x0f, 0x1f], Some(op1), None, None)
+pub fn nop_multi_reg_to(sink: &mut impl CodeSink, op1: Op1) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0x0f, 0x1f], Some(op1), None, None)
 }
+vec_encoder!(nop_multi_reg = nop_multi_reg_to(op1: Op1));
 
 /// ## ret
 
-pub fn near_ret() -> Vec<u8> {
-    inst(false, false, &[0xc3], None, None, None)
+pub fn near_ret_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xc3], None, None, None)
 }
+vec_encoder!(near_ret = near_ret_to());
 
-pub fn far_ret() -> Vec<u8> {
-    inst(false, false, &[0xcb], None, None, None)
+pub fn far_ret_to(sink: &mut impl CodeSink) -> Result<(), EncodeError> {
+    inst_to(sink, false, false, &[0xcb], None, None, None)
 }
+vec_encoder!(far_ret = far_ret_to());
 
-pub fn near_ret_imm16(imm: u16) -> Vec<u8> {
-    inst(
+pub fn near_ret_imm16_to(sink: &mut impl CodeSink, imm: u16) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
         false,
         false,
         &[0xc2],
@@ -791,9 +1589,11 @@ pub fn near_ret_imm16(imm: u16) -> Vec<u8> {
         Some(Imm(imm as u64, ImmByte::Bit16)),
     )
 }
+vec_encoder!(near_ret_imm16 = near_ret_imm16_to(imm: u16));
 
-pub fn far_ret_imm16(imm: u16) -> Vec<u8> {
-    inst(
+pub fn far_ret_imm16_to(sink: &mut impl CodeSink, imm: u16) -> Result<(), EncodeError> {
+    inst_to(
+        sink,
         false,
         false,
         &[0xca],
@@ -802,6 +1602,7 @@ pub fn far_ret_imm16(imm: u16) -> Vec<u8> {
         Some(Imm(imm as u64, ImmByte::Bit16)),
     )
 }
+vec_encoder!(far_ret_imm16 = far_ret_imm16_to(imm: u16));
 
 /// ## SSE inst
 
@@ -809,145 +1610,105 @@ pub fn far_ret_imm16(imm: u16) -> Vec<u8> {
 /// movss xmm1, xmm2/m32
 /// Merge scalar single-precision floating-point value from xmm2 to xmm1 register.
 /// Load scalar single-precision floating-point value from m32 to xmm1 register.
-pub fn movss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x10],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x10], Some(op1), Some(op2), None)
 }
+vec_encoder!(movss = movss_to(op1: Op1, op2: RegisterXmm));
 
 /// - movss_rev
 /// movss xmm2/m32, xmm1
-pub fn movss_rev(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x11],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movss_rev_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: RegisterXmm,
+) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x11], Some(op1), Some(op2), None)
 }
+vec_encoder!(movss_rev = movss_rev_to(op1: Op1, op2: RegisterXmm));
 
 /// - movsd
 /// movsd xmm1, xmm2/m32
 /// Move scalar double-precision floating-point value from xmm2 to xmm1 register.
 /// Load scalar double-precision floating-point value from m64 to xmm1 register.
-pub fn movsd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x10],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movsd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x10], Some(op1), Some(op2), None)
 }
+vec_encoder!(movsd = movsd_to(op1: Op1, op2: RegisterXmm));
 
 /// - movsd_rev
 /// movsd xmm2/m32, xmm1
-pub fn movsd_rev(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x11],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movsd_rev_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: RegisterXmm,
+) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x11], Some(op1), Some(op2), None)
 }
+vec_encoder!(movsd_rev = movsd_rev_to(op1: Op1, op2: RegisterXmm));
 
 /// - addss
 /// addss xmm1, xmm2/m32
 /// Add scalar single-precision floating-point value from xmm2 to xmm1 register.
-pub fn addss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x58],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn addss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x58], Some(op1), Some(op2), None)
 }
+vec_encoder!(addss = addss_to(op1: Op1, op2: RegisterXmm));
 
 /// - addsd
 /// addsd xmm1, xmm2/m64
 /// Add scalar double-precision floating-point value from xmm2 to xmm1 register.
-pub fn addsd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x58],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn addsd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x58], Some(op1), Some(op2), None)
 }
+vec_encoder!(addsd = addsd_to(op1: Op1, op2: RegisterXmm));
 
 /// - subss
 /// subss xmm1, xmm2/m32
 /// Subtract scalar single-precision floating-point value from xmm2 from xmm1 register.
-pub fn subss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x5c],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn subss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x5c], Some(op1), Some(op2), None)
 }
+vec_encoder!(subss = subss_to(op1: Op1, op2: RegisterXmm));
 
 /// - subsd
 /// subsd xmm1, xmm2/m64
 /// Subtract scalar double-precision floating-point value from xmm2 from xmm1 register.
-pub fn subsd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x5c],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn subsd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x5c], Some(op1), Some(op2), None)
 }
+vec_encoder!(subsd = subsd_to(op1: Op1, op2: RegisterXmm));
 
 /// - mulss
 /// mulss xmm1, xmm2/m32
 /// Multiply scalar single-precision floating-point value from xmm2 to xmm1 register.
-pub fn mulss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x59],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn mulss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x59], Some(op1), Some(op2), None)
 }
+vec_encoder!(mulss = mulss_to(op1: Op1, op2: RegisterXmm));
 
 /// - mulsd
 /// mulsd xmm1, xmm2/m64
 /// Multiply scalar double-precision floating-point value from xmm2 to xmm1 register.
-pub fn mulsd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x59],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn mulsd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x59], Some(op1), Some(op2), None)
 }
+vec_encoder!(mulsd = mulsd_to(op1: Op1, op2: RegisterXmm));
 
 /// - divss
 /// divss xmm1, xmm2/m32
 /// Divide scalar single-precision floating-point value from xmm2 by xmm1 register.
-pub fn divss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x5e],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn divss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x5e], Some(op1), Some(op2), None)
 }
+vec_encoder!(divss = divss_to(op1: Op1, op2: RegisterXmm));
 
 /// - divsd
 /// divsd xmm1, xmm2/m64
 /// Divide scalar double-precision floating-point value from xmm2 by xmm1 register.
-pub fn divsd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf2, 0x0f, 0x5e],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn divsd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf2, 0x0f, 0x5e], Some(op1), Some(op2), None)
 }
+vec_encoder!(divsd = divsd_to(op1: Op1, op2: RegisterXmm));
 
 /// - sqrtss/sqrtsd operations
 #[repr(u8)]
@@ -965,63 +1726,69 @@ pub enum FcmpOp {
 /// - cmpss
 /// cmpss xmm1, xmm2/m32
 /// Compare scalar single-precision floating-point value from xmm2 to xmm1 register.
-pub fn cmpss(op1: Op1, op2: RegisterXmm, imm: FcmpOp) -> Vec<u8> {
-    sse_inst(
+pub fn cmpss_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: RegisterXmm,
+    imm: FcmpOp,
+) -> Result<(), EncodeError> {
+    sse_inst_to(
+        sink,
         &[0xf3, 0x0f, 0xC2],
         Some(op1),
-        Some(TargetReg::from(op2 as u8)),
+        Some(op2),
         Some(Imm(imm as u64, ImmByte::Bit8)),
     )
 }
+vec_encoder!(cmpss = cmpss_to(op1: Op1, op2: RegisterXmm, imm: FcmpOp));
 
 /// - cmpsd
 /// cmpsd xmm1, xmm2/m64
 /// cmpsd(xmm2/m64, xmm1)
 /// Compare scalar double-precision floating-point value from xmm2 to xmm1 register.
-pub fn cmpsd(op1: Op1, op2: RegisterXmm, imm: FcmpOp) -> Vec<u8> {
-    sse_inst(
+pub fn cmpsd_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: RegisterXmm,
+    imm: FcmpOp,
+) -> Result<(), EncodeError> {
+    sse_inst_to(
+        sink,
         &[0xf2, 0x0f, 0xC2],
         Some(op1),
-        Some(TargetReg::from(op2 as u8)),
+        Some(op2),
         Some(Imm(imm as u64, ImmByte::Bit8)),
     )
 }
+vec_encoder!(cmpsd = cmpsd_to(op1: Op1, op2: RegisterXmm, imm: FcmpOp));
 
 /// - sqrtss
 /// sqrtss xmm1, xmm2/m32
 /// sqrtss(xmm2/mem, xmm1)
 /// Compute square root of scalar single-precision floating-point value in xmm2 and store result in xmm1.
-pub fn sqrtss(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0xf3, 0x0f, 0x51],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn sqrtss_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0xf3, 0x0f, 0x51], Some(op1), Some(op2), None)
 }
+vec_encoder!(sqrtss = sqrtss_to(op1: Op1, op2: RegisterXmm));
 
 /// ## SIMD Inst
 /// - movupd
 /// movupd xmm1, xmm2/m128
 /// movupd(xmm2/m128, xmm1)
 /// Move unaligned packed double-precision floating-point values from xmm2/mem to xmm1.
-pub fn movupd(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0x66, 0x0F, 0x10],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movupd_to(sink: &mut impl CodeSink, op1: Op1, op2: RegisterXmm) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0x66, 0x0F, 0x10], Some(op1), Some(op2), None)
 }
+vec_encoder!(movupd = movupd_to(op1: Op1, op2: RegisterXmm));
 
 /// movupd xmm2/m128, xmm1
 /// movupd_rev(xmm2/m128, xmm1)
 /// Move unaligned packed double-precision floating-point from xmm1 to xmm2/mem.
-pub fn movupd_rev(op1: Op1, op2: RegisterXmm) -> Vec<u8> {
-    sse_inst(
-        &[0x66, 0x0F, 0x10],
-        Some(op1),
-        Some(TargetReg::from(op2 as u8)),
-        None,
-    )
+pub fn movupd_rev_to(
+    sink: &mut impl CodeSink,
+    op1: Op1,
+    op2: RegisterXmm,
+) -> Result<(), EncodeError> {
+    sse_inst_to(sink, &[0x66, 0x0F, 0x11], Some(op1), Some(op2), None)
 }
+vec_encoder!(movupd_rev = movupd_rev_to(op1: Op1, op2: RegisterXmm));
@@ -1,12 +1,16 @@
+//! The x86_64 encoder. It only produces bytes and is available on every host,
+//! running the code needs an x86_64 host.
+
 pub mod inst_dump_buf;
 pub mod inst_list;
 pub mod registers;
 
-use std::panic;
-
-use registers::{modrm, AddrMode, ScaledIndex, TargetReg, APPEND_SIB};
+use crate::insts::CodeSink;
 
-use self::registers::sib;
+use registers::{
+    modrm, raw_sib, sib, AddrMode, GpReg, Register32, Register8, RegisterXmm, ScaledIndex,
+    TargetReg, APPEND_SIB, DISP32,
+};
 
 const PREFIX_LOCK: u8 = 0xF0;
 
@@ -20,6 +24,69 @@ pub const REX_W: u8 = 0b01001000; // 48
 pub const REX_R: u8 = 0b01000100; // 44
 pub const REX_X: u8 = 0b01000010; // 42
 pub const REX_B: u8 = 0b01000001; // 41
+/// no extension bits, only makes SPL, BPL, SIL and DIL addressable
+pub const REX: u8 = 0b01000000; // 40
+
+const PREFIX_OPERAND_SIZE: u8 = 0x66;
+
+/// The operand size of an instruction.
+/// Bit16 adds the 0x66 prefix, Bit64 sets REX.W, Bit8 selects the byte form of the opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperandSize {
+    Bit8,
+    Bit16,
+    Bit32,
+    Bit64,
+}
+
+/// `long_mode` of the encoders: 64 bit when true, 32 bit otherwise.
+impl From<bool> for OperandSize {
+    fn from(long_mode: bool) -> Self {
+        if long_mode {
+            OperandSize::Bit64
+        } else {
+            OperandSize::Bit32
+        }
+    }
+}
+
+impl OperandSize {
+    #[inline]
+    pub fn bits(self) -> u32 {
+        match self {
+            OperandSize::Bit8 => 8,
+            OperandSize::Bit16 => 16,
+            OperandSize::Bit32 => 32,
+            OperandSize::Bit64 => 64,
+        }
+    }
+
+    /// `byte` at Bit8, `full` otherwise.
+    #[inline]
+    fn opcode<'a>(self, byte: &'a [u8], full: &'a [u8]) -> &'a [u8] {
+        if self == OperandSize::Bit8 {
+            byte
+        } else {
+            full
+        }
+    }
+
+    /// The operand sized immediate (imm8, imm16 or imm32, sign-extended at Bit64).
+    fn imm(self, imm: u32) -> Result<Imm, EncodeError> {
+        let (max, imm_byte) = match self {
+            OperandSize::Bit8 => (u8::MAX as u32, ImmByte::Bit8),
+            OperandSize::Bit16 => (u16::MAX as u32, ImmByte::Bit16),
+            _ => (u32::MAX, ImmByte::Bit32),
+        };
+        if imm > max {
+            return Err(EncodeError::ImmOutOfRange {
+                imm: imm as u64,
+                size: self,
+            });
+        }
+        Ok(Imm(imm as u64, imm_byte))
+    }
+}
 
 ///////////////////////////////////////////////////////
 
@@ -29,8 +96,25 @@ type Sib = u8;
 #[derive(Debug, Clone, Copy)]
 pub enum Op1 {
     Direct(TargetReg),
-    DeRef(TargetReg, usize),
-    ScaleBase(TargetReg, TargetReg, ScaledIndex, usize), // base index scaleindex disp
+    /// a byte register by its legacy number, see `GpReg::Byte`
+    Byte(Register8),
+    /// `[base + disp]`
+    DeRef(TargetReg, i32),
+    ScaleBase(TargetReg, TargetReg, ScaledIndex, i32), // base index scaleindex disp
+    /// `[index * scale + disp32]`, no base
+    Index(TargetReg, ScaledIndex, i32),
+    /// `[disp32]`, an absolute address in the low 2GB (sign-extended)
+    Abs(i32),
+    /// `[rip + disp32]`, relative to the end of the instruction.
+    /// Use `inst_dump_buf::RipInst` to have a label resolved into it.
+    Rip(i32),
+}
+
+/// an XMM register as the r/m operand
+impl From<RegisterXmm> for Op1 {
+    fn from(r: RegisterXmm) -> Self {
+        Op1::Direct(r.into())
+    }
 }
 
 impl Op1 {
@@ -50,51 +134,198 @@ impl Op1 {
                     0
                 }
             }
+            Op1::Byte(_) => 0,
             Op1::ScaleBase(baser, indexr, _, _) => {
                 let a = if baser.is_extend() { REX_B } else { 0 };
                 let b = if indexr.is_extend() { REX_X } else { 0 };
                 a | b
             }
+            Op1::Index(indexr, _, _) => {
+                if indexr.is_extend() {
+                    REX_X
+                } else {
+                    0
+                }
+            }
+            Op1::Abs(_) | Op1::Rip(_) => 0,
+        }
+    }
+
+    /// the register operand, if this is one
+    fn as_reg(&self) -> Option<GpReg> {
+        match *self {
+            Op1::Direct(r) => Some(GpReg::Full(r)),
+            Op1::Byte(r) => Some(GpReg::Byte(r)),
+            _ => None,
+        }
+    }
+}
+
+/// The operand an `EncodeError` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Op1,
+    Op2,
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Op1 => write!(f, "op1"),
+            Operand::Op2 => write!(f, "op2"),
+        }
+    }
+}
+
+/// Invalid input to an encoder, nothing is written when it is returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// RSP can not be an index register (R12 can)
+    InvalidIndex { operand: Operand },
+    /// `operand` is required by the other operands, e.g. a reg operand needs an r/m operand beside it
+    MissingOperand { operand: Operand },
+    /// a register number outside its register class
+    InvalidRegister { value: u8, max: u8 },
+    /// the immediate does not fit in the operand size
+    ImmOutOfRange { imm: u64, size: OperandSize },
+    /// the register does not exist at the operand size, e.g. AH in a 32 bit instruction
+    SizeMismatch { operand: Operand, size: OperandSize },
+    /// AH, CH, DH and BH can not be encoded in an instruction with a REX prefix
+    RexConflict { operand: Operand, reg: Register8 },
+    /// the instruction has no form of this operand size
+    UnsupportedSize { size: OperandSize },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            EncodeError::InvalidIndex { operand } => {
+                write!(f, "{}: rsp can not be an index register", operand)
+            }
+            EncodeError::MissingOperand { operand } => write!(f, "{} is missing", operand),
+            EncodeError::InvalidRegister { value, max } => {
+                write!(f, "register number {} is out of 0..={}", value, max)
+            }
+            EncodeError::ImmOutOfRange { imm, size } => write!(
+                f,
+                "immediate {:#x} does not fit in {} bits",
+                imm,
+                size.bits()
+            ),
+            EncodeError::SizeMismatch { operand, size } => write!(
+                f,
+                "{}: register does not exist at {} bits",
+                operand,
+                size.bits()
+            ),
+            EncodeError::UnsupportedSize { size } => {
+                write!(f, "no {} bit form of the instruction", size.bits())
+            }
+            EncodeError::RexConflict { operand, reg } => {
+                write!(
+                    f,
+                    "{}: {:?} can not be used with a REX prefix",
+                    operand, reg
+                )
+            }
         }
     }
 }
 
-fn usize_boxed_length(u: usize) -> AddrMode {
-    if u == 0 {
-        registers::AddrMode::RegRef
-    } else if u8::MAX as usize >= u {
-        registers::AddrMode::Disp8
-    } else if u32::MAX as usize >= u {
-        registers::AddrMode::Disp32
+impl std::error::Error for EncodeError {}
+
+/// the mode of `[base + disp]`, RBP and R13 have no mode without a displacement.
+fn disp_length(base: TargetReg, disp: i32) -> AddrMode {
+    if disp == 0 && base.get_reg() != DISP32 {
+        AddrMode::RegRef
+    } else if i8::try_from(disp).is_ok() {
+        AddrMode::Disp8
+    } else {
+        AddrMode::Disp32
+    }
+}
+
+fn check_index(index: TargetReg) -> Result<(), EncodeError> {
+    if index == TargetReg::Rsp {
+        Err(EncodeError::InvalidIndex {
+            operand: Operand::Op1,
+        })
     } else {
-        panic!("usize is too big to be boxed")
+        Ok(())
     }
 }
 
-fn to_modrm_sib_disp(this: Op1, src_reg: TargetReg) -> (ModRM, Option<Sib>, Vec<u8>) {
-    match this {
+/// The displacement is returned as (mode, value), see `AddrMode::encode_disp_to`.
+/// The mode is the one of the displacement, for `Index` and `Abs` it differs from the ModRM mode.
+fn to_modrm_sib_disp(
+    this: Op1,
+    src_reg: Register32,
+) -> Result<(ModRM, Option<Sib>, AddrMode, i32), EncodeError> {
+    Ok(match this {
         Op1::Direct(reg) => (
-            modrm(AddrMode::Direct, reg.get_reg(), src_reg.get_reg()),
+            modrm(AddrMode::Direct, reg.get_reg(), src_reg),
+            None,
+            AddrMode::Direct,
+            0,
+        ),
+        Op1::Byte(reg) => (
+            modrm(AddrMode::Direct, Register32::from(reg as u8), src_reg),
             None,
-            vec![],
+            AddrMode::Direct,
+            0,
         ),
+        // RSP and R12 as r/m mean a SIB follows, they need one without index
+        Op1::DeRef(reg, disp) if reg.get_reg() == APPEND_SIB => {
+            let addr_mode = disp_length(reg, disp);
+            (
+                modrm(addr_mode, APPEND_SIB, src_reg),
+                Some(raw_sib(APPEND_SIB, ScaledIndex::Id, APPEND_SIB)),
+                addr_mode,
+                disp,
+            )
+        }
         Op1::DeRef(reg, disp) => {
-            let addr_mode = usize_boxed_length(disp);
+            let addr_mode = disp_length(reg, disp);
             (
-                modrm(addr_mode, reg.get_reg(), src_reg.get_reg()),
+                modrm(addr_mode, reg.get_reg(), src_reg),
                 None,
-                addr_mode.encode_disp(disp),
+                addr_mode,
+                disp,
             )
         }
         Op1::ScaleBase(base, index, scale, disp) => {
-            let addr_mode = usize_boxed_length(disp);
+            check_index(index)?;
+            let addr_mode = disp_length(base, disp);
             (
-                modrm(addr_mode, APPEND_SIB, src_reg.get_reg()),
+                modrm(addr_mode, APPEND_SIB, src_reg),
                 Some(sib(base, scale, index)),
-                addr_mode.encode_disp(disp),
+                addr_mode,
+                disp,
             )
         }
-    }
+        Op1::Index(index, scale, disp) => {
+            check_index(index)?;
+            (
+                modrm(AddrMode::RegRef, APPEND_SIB, src_reg),
+                Some(raw_sib(DISP32, scale, index.get_reg())),
+                AddrMode::Disp32,
+                disp,
+            )
+        }
+        // ModRM [disp32] is RIP-relative in 64 bit mode, the SIB form is absolute
+        Op1::Abs(disp) => (
+            modrm(AddrMode::RegRef, APPEND_SIB, src_reg),
+            Some(raw_sib(DISP32, ScaledIndex::Id, APPEND_SIB)),
+            AddrMode::Disp32,
+            disp,
+        ),
+        Op1::Rip(disp) => (
+            modrm(AddrMode::RegRef, DISP32, src_reg),
+            None,
+            AddrMode::Disp32,
+            disp,
+        ),
+    })
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -109,15 +340,34 @@ pub enum ImmByte {
 }
 
 impl ImmByte {
+    #[inline]
+    pub fn bytes(self) -> usize {
+        match self {
+            ImmByte::Bit8 => 1,
+            ImmByte::Bit16 => 2,
+            ImmByte::Bit32 => 4,
+            ImmByte::Bit64 => 8,
+        }
+    }
+
+    pub fn encode_to(self, sink: &mut impl CodeSink, imm: u64) {
+        match self {
+            ImmByte::Bit8 => sink.put_byte(imm as u8),
+            ImmByte::Bit16 => sink.put_bytes(&(imm as u16).to_le_bytes()),
+            ImmByte::Bit32 => sink.put_bytes(&(imm as u32).to_le_bytes()),
+            ImmByte::Bit64 => sink.put_bytes(&imm.to_le_bytes()),
+        }
+    }
+
     pub fn encode(self, imm: u64) -> Vec<u8> {
         if let ImmByte::Bit8 = self {
-            (imm as u8).to_ne_bytes().to_vec()
+            (imm as u8).to_le_bytes().to_vec()
         } else if let ImmByte::Bit16 = self {
-            (imm as u16).to_ne_bytes().to_vec()
+            (imm as u16).to_le_bytes().to_vec()
         } else if let ImmByte::Bit32 = self {
-            (imm as u32).to_ne_bytes().to_vec()
+            (imm as u32).to_le_bytes().to_vec()
         } else {
-            imm.to_ne_bytes().to_vec()
+            imm.to_le_bytes().to_vec()
         }
     }
 }
@@ -162,70 +412,275 @@ impl Imm {
     pub fn get_imm(&self) -> Vec<u8> {
         self.1.encode(self.0)
     }
+
+    #[inline]
+    pub fn encode_to(&self, sink: &mut impl CodeSink) {
+        self.1.encode_to(sink, self.0)
+    }
 }
 
-fn inst(
+fn inst_to(
+    sink: &mut impl CodeSink,
     atomic: bool,
     long_mode: bool,
     opcode: &[u8], // 0~2bytes
     op1: Option<Op1>,
     op2: Option<TargetReg>,
     imm: Option<Imm>,
-) -> Vec<u8> {
-    let prefixes = if atomic { vec![PREFIX_LOCK] } else { vec![] };
-    let opcode = if long_mode {
-        let op1_rex = op1.map(|op1| op1.rex_value()).unwrap_or(0);
-        let op2_rex = op2.map(|op2| op2.reg_value()).unwrap_or(0);
-        // warning! this logic is not tested
-        let op2_rex = if op1.is_none() { REX_B } else { op2_rex };
-        let mut r = vec![REX_W | op1_rex | op2_rex];
-        r.extend(opcode);
-        r
-    } else {
-        opcode.to_vec()
+) -> Result<(), EncodeError> {
+    sized_inst_to(
+        sink,
+        atomic,
+        long_mode.into(),
+        opcode,
+        op1,
+        op2.map(GpReg::from),
+        imm,
+    )
+}
+
+/// What ModRM.reg holds.
+#[derive(Debug, Clone, Copy)]
+enum RegField {
+    Reg(GpReg),
+    /// an opcode extension, `/digit` in the manuals
+    Digit(u8),
+}
+
+/// `opcode` is the one for `size`, see `OperandSize::opcode`.
+fn sized_inst_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: OperandSize,
+    opcode: &[u8],
+    op1: Option<Op1>,
+    op2: Option<GpReg>,
+    imm: Option<Imm>,
+) -> Result<(), EncodeError> {
+    prefixed_inst_to(
+        sink,
+        atomic,
+        size,
+        size,
+        &[],
+        opcode,
+        op1,
+        op2.map(RegField::Reg),
+        imm,
+    )
+}
+
+/// `opcode /digit`: ModRM.reg holds `digit`, an extension of the opcode.
+fn digit_inst_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: OperandSize,
+    opcode: &[u8],
+    digit: u8,
+    op1: Op1,
+    imm: Option<Imm>,
+) -> Result<(), EncodeError> {
+    prefixed_inst_to(
+        sink,
+        atomic,
+        size,
+        size,
+        &[],
+        opcode,
+        Some(op1),
+        Some(RegField::Digit(digit)),
+        imm,
+    )
+}
+
+/// `opcode +r`: the register is added to the opcode byte, there is no ModRM.
+fn plus_reg_inst_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: OperandSize,
+    opcode: u8,
+    reg: TargetReg,
+    imm: Option<Imm>,
+) -> Result<(), EncodeError> {
+    let mut rex = if reg.is_extend() { REX_B } else { 0 };
+    if size == OperandSize::Bit64 {
+        rex |= REX_W;
+    }
+    if size == OperandSize::Bit8 && GpReg::from(reg).needs_rex_as_byte() {
+        rex |= REX;
+    }
+
+    if atomic {
+        sink.put_byte(PREFIX_LOCK);
+    }
+    if size == OperandSize::Bit16 {
+        sink.put_byte(PREFIX_OPERAND_SIZE);
+    }
+    if rex != 0 {
+        sink.put_byte(rex);
+    }
+    sink.put_byte(opcode + reg.reg_value());
+    if let Some(imm) = imm {
+        imm.encode_to(sink);
+    }
+    Ok(())
+}
+
+/// movzx/movsx of a byte: `op1` is a byte whatever `size` is.
+fn extend_bit8_inst_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: OperandSize,
+    opcode: &[u8],
+    op1: Op1,
+    op2: GpReg,
+) -> Result<(), EncodeError> {
+    prefixed_inst_to(
+        sink,
+        atomic,
+        size,
+        OperandSize::Bit8,
+        &[],
+        opcode,
+        Some(op1),
+        Some(RegField::Reg(op2)),
+        None,
+    )
+}
+
+/// `mandatory_prefix` goes between the legacy prefixes and REX, which must directly precede the opcode.
+/// `op1_size` is the size of a register in `op1`, it differs from `size` only for `extend_bit8_inst_to`.
+#[allow(clippy::too_many_arguments)]
+fn prefixed_inst_to(
+    sink: &mut impl CodeSink,
+    atomic: bool,
+    size: OperandSize,
+    op1_size: OperandSize,
+    mandatory_prefix: &[u8],
+    opcode: &[u8],
+    op1: Option<Op1>,
+    reg: Option<RegField>,
+    imm: Option<Imm>,
+) -> Result<(), EncodeError> {
+    // everything that can fail comes before the first byte is written
+    let (op2, reg_field) = match reg {
+        Some(RegField::Reg(r)) => (Some(r), r.get_reg()),
+        Some(RegField::Digit(d)) => (None, Register32::from(d)),
+        None => (None, Register32::Eax),
     };
-    let (modrm, sib, disp) = match (op1, op2) {
-        (None, None) => (None, None, vec![]),
-        (None, Some(_op2)) => panic!("unsupport None, reg instruction"), //(None, None, vec![], vec![op2 as u8]),
-        (Some(op1), None) => {
-            let (mod_rm, sib, disp) = to_modrm_sib_disp(op1, TargetReg::from(0));
-            (Some(mod_rm), sib, disp)
+    let modrm_sib_disp = match (op1, reg) {
+        (None, None) => None,
+        (None, Some(_)) => {
+            return Err(EncodeError::MissingOperand {
+                operand: Operand::Op1,
+            })
         }
-        (Some(op1), Some(op2)) => {
-            let (mod_rm, sib, disp) = to_modrm_sib_disp(op1, op2);
-            (Some(mod_rm), sib, disp)
-        }
-    };
-    let imm = if let Some(imm) = imm {
-        imm.get_imm()
-    } else {
-        vec![]
+        (Some(op1), _) => Some(to_modrm_sib_disp(op1, reg_field)?),
     };
-    encode(&prefixes, &opcode, modrm, sib, &disp, &imm)
+
+    let regs = [
+        (Operand::Op1, op1_size, op1.and_then(|op1| op1.as_reg())),
+        (Operand::Op2, size, op2),
+    ];
+    let mut rex = op1.map(|op1| op1.rex_value()).unwrap_or(0);
+    if op2.map(|op2| op2.is_extend()).unwrap_or(false) {
+        rex |= REX_R;
+    }
+    if size == OperandSize::Bit64 {
+        rex |= REX_W;
+    }
+    if regs.iter().any(|(_, size, r)| {
+        *size == OperandSize::Bit8 && matches!(r, Some(r) if r.needs_rex_as_byte())
+    }) {
+        rex |= REX;
+    }
+    for (operand, size, reg) in regs {
+        if let Some(GpReg::Byte(reg)) = reg {
+            if size != OperandSize::Bit8 {
+                return Err(EncodeError::SizeMismatch { operand, size });
+            }
+            if rex != 0 && reg as u8 >= 4 {
+                return Err(EncodeError::RexConflict { operand, reg });
+            }
+        }
+    }
+
+    if atomic {
+        sink.put_byte(PREFIX_LOCK);
+    }
+    if size == OperandSize::Bit16 {
+        sink.put_byte(PREFIX_OPERAND_SIZE);
+    }
+    sink.put_bytes(mandatory_prefix);
+    if rex != 0 {
+        sink.put_byte(rex);
+    }
+    sink.put_bytes(opcode);
+    if let Some((modrm, sib, addr_mode, disp)) = modrm_sib_disp {
+        sink.put_byte(modrm);
+        if let Some(sib) = sib {
+            sink.put_byte(sib);
+        }
+        addr_mode.encode_disp_to(sink, disp);
+    }
+    if let Some(imm) = imm {
+        imm.encode_to(sink);
+    }
+    Ok(())
 }
 
-fn sse_inst(opcode: &[u8], op1: Option<Op1>, op2: Option<TargetReg>, imm: Option<Imm>) -> Vec<u8> {
-    inst(false, false, opcode, op1, op2, imm)
+/// `opcode` starts with the mandatory prefix (0x66, 0xf2 or 0xf3).
+fn sse_inst_to(
+    sink: &mut impl CodeSink,
+    opcode: &[u8],
+    op1: Option<Op1>,
+    op2: Option<RegisterXmm>,
+    imm: Option<Imm>,
+) -> Result<(), EncodeError> {
+    let (prefix, opcode) = opcode.split_at(1);
+    prefixed_inst_to(
+        sink,
+        false,
+        OperandSize::Bit32,
+        OperandSize::Bit32,
+        prefix,
+        opcode,
+        op1,
+        op2.map(|r| RegField::Reg(GpReg::Full(r.into()))),
+        imm,
+    )
 }
 
-pub fn encode(
+pub fn encode_to(
+    sink: &mut impl CodeSink,
     prefixes: &[u8],
     opcode: &[u8],
     modrm: Option<ModRM>,
     sib: Option<Sib>,
     disp: &[u8],
     imm: &[u8],
-) -> Vec<u8> {
-    let mut buf = vec![];
-    buf.extend(prefixes);
-    buf.extend(opcode);
+) {
+    sink.put_bytes(prefixes);
+    sink.put_bytes(opcode);
     if let Some(x) = modrm {
-        buf.push(x);
+        sink.put_byte(x);
     }
     if let Some(x) = sib {
-        buf.push(x);
+        sink.put_byte(x);
     }
-    buf.extend(disp);
-    buf.extend(imm);
+    sink.put_bytes(disp);
+    sink.put_bytes(imm);
+}
+
+pub fn encode(
+    prefixes: &[u8],
+    opcode: &[u8],
+    modrm: Option<ModRM>,
+    sib: Option<Sib>,
+    disp: &[u8],
+    imm: &[u8],
+) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_to(&mut buf, prefixes, opcode, modrm, sib, disp, imm);
     buf
 }
@@ -1,3 +1,7 @@
+use crate::insts::CodeSink;
+
+use super::EncodeError;
+
 macro_rules! make_register_enum {
     ($name:ident, $i0:ident, $i1:ident, $i2:ident, $i3:ident, $i4:ident, $i5:ident, $i6:ident, $i7:ident) => {
         #[repr(u8)] // 3bit
@@ -13,6 +17,16 @@ macro_rules! make_register_enum {
             $i7 = 7,
         }
 
+        impl $name {
+            pub fn try_from_u8(i: u8) -> Result<Self, EncodeError> {
+                if i <= 7 {
+                    Ok(unsafe { std::mem::transmute_copy(&i) })
+                } else {
+                    Err(EncodeError::InvalidRegister { value: i, max: 7 })
+                }
+            }
+        }
+
         impl From<u8> for $name {
             fn from(i: u8) -> Self {
                 assert!(i <= 7, "Register::from(u8): i must be <= 7");
@@ -26,9 +40,50 @@ make_register_enum!(Register8, AL, CL, DL, BL, AH, CH, DH, BH);
 make_register_enum!(Register16, AX, CX, DX, BX, SP, BP, SI, DI);
 make_register_enum!(Register32, Eax, Ecx, Edx, Ebx, Esp, Ebp, Esi, Edi);
 // make_register_enum!(RegisterMme, MM0, MM1, MM2, MM3, MM4, MM5, MM6, MM7);
-make_register_enum!(RegisterXmm, XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7);
 
-#[cfg(target_arch = "x86_64")]
+#[repr(u8)] // 4bit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegisterXmm {
+    XMM0 = 0,
+    XMM1 = 1,
+    XMM2 = 2,
+    XMM3 = 3,
+    XMM4 = 4,
+    XMM5 = 5,
+    XMM6 = 6,
+    XMM7 = 7,
+    XMM8 = 8,
+    XMM9 = 9,
+    XMM10 = 10,
+    XMM11 = 11,
+    XMM12 = 12,
+    XMM13 = 13,
+    XMM14 = 14,
+    XMM15 = 15,
+}
+impl RegisterXmm {
+    pub fn try_from_u8(i: u8) -> Result<Self, EncodeError> {
+        if i <= 15 {
+            Ok(unsafe { std::mem::transmute_copy(&i) })
+        } else {
+            Err(EncodeError::InvalidRegister { value: i, max: 15 })
+        }
+    }
+}
+impl From<u8> for RegisterXmm {
+    fn from(i: u8) -> Self {
+        assert!(i <= 15, "RegisterXmm::from(u8): i must be <= 15");
+        unsafe { std::mem::transmute_copy(&i) }
+    }
+}
+
+/// the same number as a `TargetReg`, the encoding of both is the same
+impl From<RegisterXmm> for TargetReg {
+    fn from(r: RegisterXmm) -> Self {
+        TargetReg::from(r as u8)
+    }
+}
+
 #[repr(u8)] // 4bit
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Register64 {
@@ -49,7 +104,15 @@ pub enum Register64 {
     R14 = 14,
     R15 = 15,
 }
-#[cfg(target_arch = "x86_64")]
+impl Register64 {
+    pub fn try_from_u8(i: u8) -> Result<Self, EncodeError> {
+        if i <= 15 {
+            Ok(unsafe { std::mem::transmute_copy(&i) })
+        } else {
+            Err(EncodeError::InvalidRegister { value: i, max: 15 })
+        }
+    }
+}
 impl From<u8> for Register64 {
     fn from(i: u8) -> Self {
         assert!(i <= 15, "Register64::from(u8): i must be <= 15");
@@ -90,6 +153,50 @@ impl Register64 {
     }
 }
 
+/// A general purpose register operand, its width is the instruction's `OperandSize`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GpReg {
+    /// The low 8/16/32 bits or all of the register.
+    /// At 8 bits Rsp..Rdi are SPL..DIL, which need a REX prefix.
+    Full(TargetReg),
+    /// A byte register by its legacy number, the only way to name AH..BH.
+    /// AH..BH can not be used together with a REX prefix.
+    Byte(Register8),
+}
+
+impl From<TargetReg> for GpReg {
+    fn from(r: TargetReg) -> Self {
+        GpReg::Full(r)
+    }
+}
+
+impl From<Register8> for GpReg {
+    fn from(r: Register8) -> Self {
+        GpReg::Byte(r)
+    }
+}
+
+impl GpReg {
+    pub fn get_reg(&self) -> Register32 {
+        match self {
+            GpReg::Full(r) => r.get_reg(),
+            GpReg::Byte(r) => Register32::from(*r as u8),
+        }
+    }
+
+    pub fn is_extend(&self) -> bool {
+        match self {
+            GpReg::Full(r) => r.is_extend(),
+            GpReg::Byte(_) => false,
+        }
+    }
+
+    /// SPL, BPL, SIL or DIL when used as a byte register
+    pub fn needs_rex_as_byte(&self) -> bool {
+        matches!(self, GpReg::Full(r) if (4..8).contains(&(*r as u8)))
+    }
+}
+
 pub static APPEND_SIB: Register32 = unsafe { std::mem::transmute_copy(&4u8) };
 pub static DISP32: Register32 = unsafe { std::mem::transmute_copy(&5u8) };
 
@@ -112,14 +219,27 @@ pub enum ScaledIndex {
 
 #[inline]
 pub fn sib(base: TargetReg, scale: ScaledIndex, index: TargetReg) -> u8 {
-    // sib_check(&base, &index).unwrap();
+    raw_sib(base.get_reg(), scale, index.get_reg())
+}
+
+/// `APPEND_SIB` as index is no index, `DISP32` as base with `AddrMode::RegRef` is no base.
+#[inline]
+pub fn raw_sib(base: Register32, scale: ScaledIndex, index: Register32) -> u8 {
     let r = base as u8;
     let r = r + ((index as u8) << 3u8);
     r + ((scale as u8) << 6u8)
 }
 
 impl AddrMode {
-    pub fn encode_disp(&self, disp: usize) -> Vec<u8> {
+    pub fn encode_disp_to(&self, sink: &mut impl CodeSink, disp: i32) {
+        match *self {
+            AddrMode::Disp8 => sink.put_byte(disp as u8),
+            AddrMode::Disp32 => sink.put_bytes(&disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    pub fn encode_disp(&self, disp: i32) -> Vec<u8> {
         let mut r = Vec::new();
         match *self {
             AddrMode::Disp8 => {
@@ -0,0 +1,81 @@
+use std::marker::PhantomData;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Function pointer types a `JitFunction` can be called as.
+pub trait JitFn: Copy + sealed::Sealed {}
+
+/// A typed entry into installed code.
+///
+/// It borrows the memory owner (`PageHandle`, `CodeCache`) for `'a`,
+/// so the owner can not be dropped while the function is still reachable.
+#[derive(Debug)]
+pub struct JitFunction<'a, F: JitFn> {
+    ptr: *const u8,
+    _marker: PhantomData<(&'a (), F)>,
+}
+
+impl<'a, F: JitFn> Clone for JitFunction<'a, F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, F: JitFn> Copy for JitFunction<'a, F> {}
+
+// only a code address, calling it is already `unsafe`.
+unsafe impl<'a, F: JitFn> Send for JitFunction<'a, F> {}
+unsafe impl<'a, F: JitFn> Sync for JitFunction<'a, F> {}
+
+impl<'a, F: JitFn> JitFunction<'a, F> {
+    /// # Safety
+    /// `ptr` must point to code with the signature `F` that stays executable for `'a`.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *const u8) -> Self {
+        JitFunction {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// # Safety
+    /// the returned pointer is not tied to `'a`, the caller must not call it after the owner is dropped.
+    #[inline]
+    pub unsafe fn as_raw_fn(&self) -> F {
+        std::mem::transmute_copy(&self.ptr)
+    }
+}
+
+macro_rules! impl_jit_fn {
+    ($($arg:ident),*) => {
+        impl<R, $($arg),*> sealed::Sealed for extern "C" fn($($arg),*) -> R {}
+        impl<R, $($arg),*> JitFn for extern "C" fn($($arg),*) -> R {}
+
+        impl<'a, R, $($arg),*> JitFunction<'a, extern "C" fn($($arg),*) -> R> {
+            /// # Safety
+            /// the installed code must be valid for the signature it was obtained with.
+            #[allow(non_snake_case, clippy::too_many_arguments)]
+            #[inline]
+            pub unsafe fn call(&self, $($arg: $arg),*) -> R {
+                (self.as_raw_fn())($($arg),*)
+            }
+        }
+    };
+}
+
+impl_jit_fn!();
+impl_jit_fn!(A);
+impl_jit_fn!(A, B);
+impl_jit_fn!(A, B, C);
+impl_jit_fn!(A, B, C, D);
+impl_jit_fn!(A, B, C, D, E);
+impl_jit_fn!(A, B, C, D, E, G);
+impl_jit_fn!(A, B, C, D, E, G, H);
+impl_jit_fn!(A, B, C, D, E, G, H, I);
@@ -0,0 +1,213 @@
+//! Lazy compilation stubs (x86_64).
+//!
+//! Each stub is a function pointer handed out before the function is compiled:
+//!
+//! ```text
+//! stub_i:  jmp [rip + slot_i]       ; slot_i = lazy_i until compiled, then the compiled code
+//! lazy_i:  mov r11, &entries[i]
+//!          jmp resolver
+//! ```
+//!
+//! The shared resolver saves the argument registers, calls the compile callback of the stub once
+//! and stores the result in the slot, then jumps to the compiled code with the original arguments.
+//! Later calls only go through the indirect jump.
+//! Concurrent first calls of the same stub wait for the one compilation.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use crate::{
+    code_module::{CodeModule, ModuleLayout},
+    jit_function::{JitFn, JitFunction},
+    page_manage::{PageError, PageSize},
+};
+
+/// Compiles the function behind a stub and returns its entry.
+/// The code must stay executable as long as the stubs are alive.
+/// It runs on the thread of the first call, panicking aborts the process.
+pub type CompileFn = Box<dyn FnOnce() -> *const u8 + Send>;
+
+const STUB_SIZE: usize = 32;
+
+struct StubEntry {
+    compile: Mutex<Option<CompileFn>>,
+    /// the address of the slot in the rwdata of the module
+    slot: AtomicUsize,
+}
+
+impl StubEntry {
+    #[inline]
+    fn slot(&self) -> &AtomicUsize {
+        unsafe { &*(self.slot.load(Ordering::Relaxed) as *const AtomicUsize) }
+    }
+}
+
+extern "C" fn lazy_resolve(entry: *const StubEntry) -> usize {
+    let entry = unsafe { &*entry };
+    let mut compile = entry.compile.lock().unwrap();
+    if let Some(f) = compile.take() {
+        entry.slot().store(f() as usize, Ordering::Release);
+    }
+    entry.slot().load(Ordering::Acquire)
+}
+
+/// push the argument registers, call `lazy_resolve(r11)`, restore them and jump to the result.
+fn resolver_code() -> Vec<u8> {
+    let mut code = vec![
+        0x57, // push rdi
+        0x56, // push rsi
+        0x52, // push rdx
+        0x51, // push rcx
+        0x41, 0x50, // push r8
+        0x41, 0x51, // push r9
+        0x50, // push rax, al is the vector register count of varargs calls
+        0x48, 0x81, 0xec, 0x80, 0x00, 0x00, 0x00, // sub rsp, 128
+    ];
+    // movdqu [rsp + 16 * i], xmm<i>
+    for i in 0..8u8 {
+        code.extend([0xf3, 0x0f, 0x7f, 0x44 | (i << 3), 0x24, 16 * i]);
+    }
+    #[cfg(not(windows))]
+    code.extend([0x4c, 0x89, 0xdf]); // mov rdi, r11
+    #[cfg(windows)]
+    code.extend([
+        0x4c, 0x89, 0xd9, // mov rcx, r11
+        0x48, 0x83, 0xec, 0x20, // sub rsp, 32 ; shadow space
+    ]);
+    code.extend([0x48, 0xb8]); // mov rax, imm64
+    code.extend((lazy_resolve as *const () as u64).to_le_bytes());
+    code.extend([0xff, 0xd0]); // call rax
+    #[cfg(windows)]
+    code.extend([0x48, 0x83, 0xc4, 0x20]); // add rsp, 32
+    code.extend([0x49, 0x89, 0xc3]); // mov r11, rax
+
+    // movdqu xmm<i>, [rsp + 16 * i]
+    for i in 0..8u8 {
+        code.extend([0xf3, 0x0f, 0x6f, 0x44 | (i << 3), 0x24, 16 * i]);
+    }
+    code.extend([
+        0x48, 0x81, 0xc4, 0x80, 0x00, 0x00, 0x00, // add rsp, 128
+        0x58, // pop rax
+        0x41, 0x59, // pop r9
+        0x41, 0x58, // pop r8
+        0x59, // pop rcx
+        0x5a, // pop rdx
+        0x5e, // pop rsi
+        0x5f, // pop rdi
+        0x41, 0xff, 0xe3, // jmp r11
+    ]);
+    code
+}
+
+/// A set of lazy stubs sharing one resolver, see the module documentation.
+pub struct LazyStubs {
+    module: CodeModule,
+    /// boxed, the stubs point into it
+    entries: Box<[StubEntry]>,
+    stubs_offset: usize,
+}
+
+impl std::fmt::Debug for LazyStubs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazyStubs")
+            .field("module", &self.module)
+            .field("len", &self.entries.len())
+            .finish()
+    }
+}
+
+impl LazyStubs {
+    #[inline]
+    pub fn new(page_size: PageSize, compilers: Vec<CompileFn>) -> Self {
+        Self::try_new(page_size, compilers).unwrap()
+    }
+
+    /// one stub per callback, in order.
+    pub fn try_new(page_size: PageSize, compilers: Vec<CompileFn>) -> Result<Self, PageError> {
+        let entries = compilers
+            .into_iter()
+            .map(|f| StubEntry {
+                compile: Mutex::new(Some(f)),
+                slot: AtomicUsize::new(0),
+            })
+            .collect::<Box<[_]>>();
+
+        let mut code = resolver_code();
+        code.resize((code.len() + 15) & !15, 0xcc);
+        let stubs_offset = code.len();
+        let layout = ModuleLayout::new(
+            page_size,
+            stubs_offset + STUB_SIZE * entries.len(),
+            0,
+            8 * entries.len(),
+        );
+        for (i, entry) in entries.iter().enumerate() {
+            let stub = code.len();
+            // jmp [rip + slot_i]
+            code.extend([0xff, 0x25]);
+            let slot = ModuleLayout::rel32(stub + 6, layout.rwdata_offset + 8 * i).unwrap();
+            code.extend(slot.to_le_bytes());
+            // mov r11, &entries[i]
+            code.extend([0x49, 0xbb]);
+            code.extend((entry as *const StubEntry as u64).to_le_bytes());
+            // jmp resolver
+            code.push(0xe9);
+            code.extend(ModuleLayout::rel32(stub + 21, 0).unwrap().to_le_bytes());
+            code.resize(stub + STUB_SIZE, 0xcc);
+        }
+
+        let module = CodeModule::try_from_layout(page_size, layout, &code, &[], &[])?;
+        for (i, entry) in entries.iter().enumerate() {
+            let slot = unsafe { module.rwdata_ptr().add(8 * i) } as usize;
+            entry.slot.store(slot, Ordering::Relaxed);
+            let lazy = module.code_ptr() as usize + stubs_offset + STUB_SIZE * i + 6;
+            entry.slot().store(lazy, Ordering::Release);
+        }
+        Ok(LazyStubs {
+            module,
+            entries,
+            stubs_offset,
+        })
+    }
+
+    #[inline]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// the function pointer to hand out for stub `index`.
+    #[inline]
+    pub fn get_ptr(&self, index: usize) -> *const u8 {
+        assert!(index < self.len(), "LazyStubs: index invalid");
+        unsafe {
+            self.module
+                .code_ptr()
+                .add(self.stubs_offset + STUB_SIZE * index)
+        }
+    }
+
+    /// # Safety
+    /// the compiled function must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(&self, index: usize) -> JitFunction<'_, F> {
+        JitFunction::from_raw(self.get_ptr(index))
+    }
+
+    /// whether the first call of stub `index` has finished compiling.
+    pub fn is_compiled(&self, index: usize) -> bool {
+        self.compiled(index).is_some()
+    }
+
+    /// the compiled code behind stub `index`.
+    pub fn compiled(&self, index: usize) -> Option<*const u8> {
+        let entry = &self.entries[index];
+        let lazy = self.get_ptr(index) as usize + 6;
+        match entry.slot().load(Ordering::Acquire) {
+            target if target == lazy => None,
+            target => Some(target as *const u8),
+        }
+    }
+}
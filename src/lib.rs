@@ -1,7 +1,25 @@
 #![doc=include_str!("../README.md")]
 
+pub mod code_cache;
+pub mod code_module;
+pub mod compile_service;
+pub mod function_table;
+#[cfg(target_pointer_width = "64")]
+pub mod gdb_jit;
 pub mod insts;
+pub mod jit_function;
+#[cfg(target_arch = "x86_64")]
+pub mod lazy_stub;
 pub mod page_manage;
+#[cfg(target_arch = "x86_64")]
+pub mod patch_site;
+pub mod profiler;
+pub mod reclaim;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod trap;
 
 /*
 macro_rules! debug_code_no_ret {
@@ -10,8 +28,8 @@ macro_rules! debug_code_no_ret {
         let mut r = $code;
         r.append(&mut near_ret());
         let r = PageHandle::from(page_size, &r);
-        let code: extern "C" fn() = unsafe { std::mem::transmute(r.get_ptr()) };
-        dbg!((code)());
+        let code = unsafe { r.get_function::<extern "C" fn()>(0) };
+        dbg!(unsafe { code.call() });
     };
 }
  */
@@ -83,7 +101,1349 @@ fn x86_64_test() {
 
     let r = PageHandle::from(PageSize::from(src.capacity()), &src);
 
-    let code: extern "C" fn(u64) -> u64 = unsafe { std::mem::transmute(r.get_ptr()) };
+    let code = unsafe { r.get_function::<extern "C" fn(u64) -> u64>(0) };
+
+    assert_eq!(unsafe { code.call(1) }, 5);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn dual_mapped_page_test() {
+    use crate::page_manage::PageHandle;
+    use crate::page_manage::PageSize;
+
+    // mov eax, edi; add eax, 4; ret
+    let src = [0x89, 0xf8, 0x83, 0xc0, 0x04, 0xc3];
+    let r = PageHandle::from_dual(PageSize::from_system(), &src);
+    assert!(r.is_dual_mapped());
+    assert_ne!(r.get_ptr(), r.get_write_ptr().unwrap() as *const u8);
 
+    let code: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(r.get_ptr()) };
     assert_eq!((code)(1), 5);
+
+    unsafe { *r.get_write_ptr().unwrap().add(4) = 0x08 };
+    assert_eq!((code)(1), 9);
+}
+
+#[test]
+fn code_cache_test() {
+    use crate::code_cache::CodeCache;
+    use crate::page_manage::PageSize;
+
+    let page_size = PageSize::from_system();
+    let mut cache = CodeCache::new(page_size, page_size.0);
+
+    let a = cache.install(&[0xc3; 10], 16);
+    let b = cache.install(&[0xc3; 10], 16);
+    assert_eq!(a.offset(), 0);
+    assert_eq!(b.offset(), 16);
+    assert_eq!(cache.stats().regions, 1);
+    assert_eq!(cache.stats().used_bytes, 20);
+    assert_eq!(unsafe { *cache.get_ptr(&b) }, 0xc3);
+
+    cache.free(a).unwrap();
+    let stats = cache.stats();
+    assert_eq!(stats.free_blocks, 2);
+    assert!(stats.fragmentation > 0.0);
+
+    // the freed range is reused
+    let c = cache.alloc(8, 8);
+    assert_eq!(c.offset(), 0);
+
+    cache.free(b).unwrap();
+    cache.free(c).unwrap();
+    let stats = cache.stats();
+    assert_eq!(stats.used_bytes, 0);
+    assert_eq!(stats.free_blocks, 1);
+    assert_eq!(stats.fragmentation, 0.0);
+
+    // too big for the first region
+    let d = cache.alloc(page_size.0 * 2, 16);
+    assert_eq!(d.region(), 1);
+
+    // a block of another cache matches a live range here, but is not ours to free
+    let mut other = CodeCache::new(page_size, page_size.0);
+    let e = cache.alloc(16, 16);
+    let foreign = other.alloc(16, 16);
+    assert_eq!(
+        (e.region(), e.offset()),
+        (foreign.region(), foreign.offset())
+    );
+    let used = cache.stats().used_bytes;
+    assert!(cache.free(foreign).is_err());
+    assert_eq!(cache.stats().used_bytes, used);
+    cache.free(e).unwrap();
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn patch_page_test() {
+    use crate::page_manage::PageHandle;
+    use crate::page_manage::PageSize;
+
+    // mov eax, edi; add eax, 4; ret
+    let src = [0x89, 0xf8, 0x83, 0xc0, 0x04, 0xc3];
+    for r in [
+        PageHandle::from(PageSize::from_system(), &src),
+        PageHandle::from_dual(PageSize::from_system(), &src),
+    ] {
+        let code = unsafe { r.get_function::<extern "C" fn(u32) -> u32>(0) };
+        assert_eq!(unsafe { code.call(1) }, 5);
+        r.patch(4, &[0x10]);
+        assert_eq!(unsafe { code.call(1) }, 17);
+    }
+}
+
+#[test]
+fn page_error_test() {
+    use crate::page_manage::{PageError, PageHandle, PageSize};
+
+    let r = PageHandle::try_from(PageSize::from_system(), &[0xc3; 16]).unwrap();
+    assert_eq!(
+        r.try_patch(8, &[0x90; 16]),
+        Err(PageError::OutOfRange { offset: 8, len: 16 })
+    );
+    assert!(matches!(
+        PageHandle::try_new(usize::MAX - 4096, PageSize::from_system()),
+        Err(PageError::Alloc(_))
+    ));
+    assert_eq!(
+        PageError::OutOfRange { offset: 8, len: 16 }.to_string(),
+        "range of 16 bytes at offset 8 out of installed code"
+    );
+
+    // sizes that overflow once rounded up or guarded
+    let page_size = PageSize::from_system();
+    assert_eq!(page_size.checked_round_up(usize::MAX), None);
+    assert_eq!(page_size.checked_round_up(1), Some(page_size.0));
+    let huge = usize::MAX - page_size.0 + 1;
+    assert!(matches!(
+        PageHandle::try_new(usize::MAX, page_size),
+        Err(PageError::Alloc(_))
+    ));
+    assert!(matches!(
+        PageHandle::try_new_guarded(huge, page_size),
+        Err(PageError::Alloc(_))
+    ));
+    assert!(matches!(
+        PageHandle::try_new_dual_guarded(huge, page_size),
+        Err(PageError::Alloc(_))
+    ));
+    assert!(matches!(
+        PageHandle::try_new_near(usize::MAX, page_size, page_error_test as *const u8),
+        Err(PageError::Alloc(_))
+    ));
+}
+
+#[test]
+fn near_page_test() {
+    use crate::page_manage::{PageHandle, PageSize};
+
+    let target = near_page_test as *const u8;
+    let r = PageHandle::try_new_near(4096, PageSize::from_system(), target).unwrap();
+    assert!(r.is_near(target));
+    let r = PageHandle::try_new_dual_near(4096, PageSize::from_system(), target).unwrap();
+    assert!(r.is_near(target));
+}
+
+#[test]
+fn guard_page_test() {
+    use crate::page_manage::{find_guard_page, GuardSide, PageHandle, PageSize};
+
+    let page_size = PageSize::from_system();
+    let r = PageHandle::new_guarded(16, page_size);
+    let end = unsafe { r.get_ptr().add(r.cap()) };
+    assert!(r.is_guard_addr(end));
+    assert!(!r.is_guard_addr(r.get_ptr()));
+    assert_eq!(find_guard_page(end).unwrap().side, GuardSide::Above);
+    assert_eq!(
+        find_guard_page(unsafe { r.get_ptr().sub(1) }).unwrap().side,
+        GuardSide::Below
+    );
+    r.store(&[0xc3]);
+
+    let d = PageHandle::new_dual_guarded(16, page_size);
+    assert!(d.is_guard_addr(unsafe { d.get_ptr().add(d.cap()) }));
+    drop(r);
+    assert_eq!(find_guard_page(end), None);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn profiler_test() {
+    use crate::page_manage::{PageHandle, PageSize};
+
+    profiler::enable_perf_map().unwrap();
+    profiler::enable_jitdump().unwrap();
+    let r = PageHandle::from_named(PageSize::from_system(), &[0xc3], "emei_profiler_test");
+    profiler::disable();
+
+    let map = std::fs::read_to_string(profiler::perf_map_path()).unwrap();
+    assert!(map.contains(&format!("{:x} 1 emei_profiler_test", r.get_ptr() as usize)));
+    let dump = std::fs::read(profiler::jitdump_path()).unwrap();
+    assert_eq!(&dump[..4], &0x4A695444u32.to_ne_bytes());
+    assert!(dump.ends_with(b"emei_profiler_test\0\xc3"));
+
+    std::fs::remove_file(profiler::perf_map_path()).unwrap();
+    std::fs::remove_file(profiler::jitdump_path()).unwrap();
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn gdb_jit_test() {
+    use crate::gdb_jit::{build_elf, register_code, registered_count, DebugInfo};
+    use crate::page_manage::{PageHandle, PageSize};
+
+    let r = PageHandle::from(PageSize::from_system(), &[0x90, 0xc3]);
+    let mut info = DebugInfo::from_symbol("emei_gdb_jit_test", r.len());
+    info.file = Some("emei_gdb_jit_test.s".to_string());
+    info.lines = vec![(0, 1), (1, 2)];
+
+    let elf = build_elf(r.get_ptr(), r.len(), &info);
+    assert_eq!(&elf[..4], b"\x7fELF");
+    assert!(elf.windows(17).any(|w| w == b"emei_gdb_jit_test"));
+
+    let before = registered_count();
+    r.register_gdb(&info);
+    assert_eq!(registered_count(), before + 1);
+    // an image inside the pages goes with them too
+    register_code(unsafe { r.get_ptr().add(1) }, 1, &info);
+    assert_eq!(registered_count(), before + 2);
+    drop(r);
+    assert_eq!(registered_count(), before);
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn trap_test() {
+    use std::collections::HashMap;
+
+    use crate::page_manage::{PageHandle, PageSize};
+    use crate::trap::{call_catching, install, register_code, take_last_trap};
+    use nix::sys::signal::Signal;
+
+    install().unwrap();
+
+    // ud2; ret
+    let r = PageHandle::from(PageSize::from_system(), &[0x0f, 0x0b, 0xc3]);
+    let labels = HashMap::from([("entry".to_string(), 0)]);
+    register_code(r.get_ptr(), r.len(), &labels, None).unwrap();
+    let trap = unsafe { call_catching(r.get_ptr(), &[]) }.unwrap_err();
+    assert_eq!(trap.signal, Signal::SIGILL);
+    assert_eq!(trap.pc, r.get_ptr() as usize);
+    let location = trap.location.unwrap();
+    assert_eq!(location.offset, 0);
+    assert_eq!(location.label, Some(("entry".to_string(), 0)));
+
+    // ud2; recovery: mov eax, 42; ret
+    let r = PageHandle::from(
+        PageSize::from_system(),
+        &[0x0f, 0x0b, 0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3],
+    );
+    let recovery = unsafe { r.get_ptr().add(2) };
+    register_code(r.get_ptr(), r.len(), &HashMap::new(), Some(recovery)).unwrap();
+    let f = unsafe { r.get_function::<extern "C" fn() -> u32>(0) };
+    assert_eq!(unsafe { f.call() }, 42);
+    let trap = take_last_trap().unwrap();
+    assert_eq!(trap.signal, Signal::SIGILL);
+    assert_eq!(trap.location.unwrap().offset, 0);
+    assert!(take_last_trap().is_none());
+}
+
+#[test]
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn trap_unregister_test() {
+    use std::collections::HashMap;
+
+    use crate::code_cache::CodeCache;
+    use crate::page_manage::PageSize;
+    use crate::trap::{locate, register_code};
+
+    let page_size = PageSize::from_system();
+    let mut cache = CodeCache::new(page_size, page_size.0);
+    let first = cache.install(&[0xc3; 16], 16);
+    let second = cache.install(&[0xc3; 16], 16);
+    let (p1, p2) = (
+        cache.get_ptr(&first) as usize,
+        cache.get_ptr(&second) as usize,
+    );
+    register_code(p1 as *const u8, 16, &HashMap::new(), None).unwrap();
+    register_code(p2 as *const u8, 16, &HashMap::new(), None).unwrap();
+    assert!(locate(p1).is_some());
+
+    // a freed block can be handed out again, its registration goes with it
+    cache.free(first).unwrap();
+    assert!(locate(p1).is_none());
+    // so do the blocks still alive when the cache is dropped
+    assert!(locate(p2).is_some());
+    drop(cache);
+    assert!(locate(p2).is_none());
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn shared_code_cache_test() {
+    use crate::code_cache::SharedCodeCache;
+    use crate::page_manage::PageSize;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let codes = (0..8u8)
+        .map(|i| {
+            let cache = cache.clone();
+            // mov eax, i; ret
+            std::thread::spawn(move || cache.install(&[0xb8, i, 0, 0, 0, 0xc3], 16))
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(cache.stats().used_bytes, 8 * 6);
+
+    std::thread::scope(|s| {
+        for (i, code) in codes.iter().enumerate() {
+            s.spawn(move || {
+                let f = unsafe { code.get_function::<extern "C" fn() -> u32>(0) };
+                assert_eq!(unsafe { f.call() }, i as u32);
+            });
+        }
+    });
+
+    let first = codes[0].clone();
+    drop(codes);
+    assert_eq!(cache.stats().used_bytes, 6);
+    drop(first);
+    assert_eq!(cache.stats().used_bytes, 0);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn reclaim_test() {
+    use crate::code_cache::SharedCodeCache;
+    use crate::page_manage::PageSize;
+    use crate::reclaim::Reclaimer;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let reclaimer = Reclaimer::new();
+    let running = reclaimer.register();
+    let idle = reclaimer.register();
+    idle.offline();
+
+    // ret
+    let code = cache.install(&[0xc3], 1);
+    let f = unsafe { code.get_function::<extern "C" fn()>(0) };
+    unsafe { f.call() };
+    reclaimer.retire(code);
+
+    // `running` may still be inside the code
+    assert_eq!(reclaimer.try_reclaim(), 0);
+    assert_eq!(cache.stats().used_bytes, 1);
+
+    running.quiescent();
+    assert_eq!(reclaimer.try_reclaim(), 1);
+    assert_eq!(reclaimer.pending(), 0);
+    assert_eq!(cache.stats().used_bytes, 0);
+
+    // participants registered after the retirement never hold it back
+    reclaimer.retire(cache.install(&[0xc3], 1));
+    running.offline();
+    let _late = reclaimer.register();
+    assert_eq!(reclaimer.try_reclaim(), 1);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn code_module_test() {
+    use crate::code_module::{CodeModule, ModuleLayout};
+    use crate::page_manage::{PageError, PageSize};
+
+    let page_size = PageSize::from_system();
+    let layout = ModuleLayout::new(page_size, 15, 8, 8);
+    // mov rax, [rip + rodata]
+    let mut code = vec![0x48, 0x8b, 0x05];
+    code.extend(
+        ModuleLayout::rel32(7, layout.rodata_offset)
+            .unwrap()
+            .to_le_bytes(),
+    );
+    // add [rip + rwdata], rax
+    code.extend([0x48, 0x01, 0x05]);
+    code.extend(
+        ModuleLayout::rel32(14, layout.rwdata_offset)
+            .unwrap()
+            .to_le_bytes(),
+    );
+    // ret
+    code.push(0xc3);
+
+    let m = CodeModule::try_from_layout(
+        page_size,
+        layout,
+        &code,
+        &21u64.to_le_bytes(),
+        &1u64.to_le_bytes(),
+    )
+    .unwrap();
+    assert_eq!(
+        m.rodata_ptr() as usize - m.code_ptr() as usize,
+        layout.rodata_offset
+    );
+    let f = unsafe { m.get_function::<extern "C" fn() -> u64>(0) }.unwrap();
+    assert_eq!(unsafe { f.call() }, 21);
+    assert_eq!(unsafe { f.call() }, 21);
+    assert_eq!(unsafe { *(m.rwdata_ptr() as *const u64) }, 43);
+    assert!(unsafe { m.get_function::<extern "C" fn() -> u64>(layout.code_len) }.is_none());
+
+    // rodata larger than its part of the layout
+    assert!(matches!(
+        CodeModule::try_from_layout(page_size, layout, &code, &vec![0; page_size.0 + 1], &[]),
+        Err(PageError::Layout)
+    ));
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn lazy_stub_test() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::code_cache::SharedCodeCache;
+    use crate::lazy_stub::{CompileFn, LazyStubs};
+    use crate::page_manage::PageSize;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let compiled = Arc::new(AtomicUsize::new(0));
+    let compiler = |code: &'static [u8]| -> CompileFn {
+        let cache = cache.clone();
+        let compiled = compiled.clone();
+        Box::new(move || {
+            compiled.fetch_add(1, Ordering::SeqCst);
+            let code = cache.install(code, 16);
+            let ptr = code.get_ptr();
+            // keep the code alive for the rest of the test
+            std::mem::forget(code);
+            ptr
+        })
+    };
+    let stubs = LazyStubs::new(
+        PageSize::from_system(),
+        vec![
+            // lea rax, [rdi + rsi]; ret
+            compiler(&[0x48, 0x8d, 0x04, 0x37, 0xc3]),
+            // mov rax, rdx; ret
+            compiler(&[0x48, 0x89, 0xd0, 0xc3]),
+        ],
+    );
+    assert!(!stubs.is_compiled(0));
+
+    let add = unsafe { stubs.get_function::<extern "C" fn(u64, u64) -> u64>(0) };
+    assert_eq!(unsafe { add.call(1, 2) }, 3);
+    assert!(stubs.is_compiled(0));
+    assert_eq!(unsafe { add.call(3, 4) }, 7);
+    assert_eq!(compiled.load(Ordering::SeqCst), 1);
+
+    let third = unsafe { stubs.get_function::<extern "C" fn(u64, u64, u64) -> u64>(1) };
+    let results = std::thread::scope(|s| {
+        (0..4u64)
+            .map(|i| s.spawn(move || unsafe { third.call(0, 0, i) }))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(results, vec![0, 1, 2, 3]);
+    assert_eq!(compiled.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn compile_service_test() {
+    use std::sync::mpsc::channel;
+
+    use crate::code_cache::SharedCodeCache;
+    use crate::compile_service::{CompileError, CompileService, SubmitError};
+    use crate::insts::x86_64::{
+        inst_dump_buf::{InstBuffer, JumpInst},
+        ImmByte,
+    };
+    use crate::page_manage::PageSize;
+
+    let service = CompileService::new(SharedCodeCache::new(PageSize::from_system(), 1), 1, 1);
+    let (done, results) = channel();
+
+    let ok = done.clone();
+    service
+        .submit(
+            Box::new(|| {
+                let buf = InstBuffer::default();
+                // mov eax, 5; ret
+                buf.inst(vec![0xb8, 5, 0, 0, 0]);
+                buf.inst(vec![0xc3]);
+                buf
+            }),
+            16,
+            Box::new(move |r| ok.send(r).unwrap()),
+        )
+        .unwrap();
+    let code = results.recv().unwrap().unwrap();
+    let f = unsafe { code.get_function::<extern "C" fn() -> u32>(0) };
+    assert_eq!(unsafe { f.call() }, 5);
+
+    let link = done.clone();
+    service
+        .submit(
+            Box::new(|| {
+                let buf = InstBuffer::default();
+                buf.jump(JumpInst::from(
+                    vec![0xe9, 0, 0, 0, 0],
+                    ImmByte::Bit32,
+                    "nowhere".to_string(),
+                ));
+                buf
+            }),
+            16,
+            Box::new(move |r| link.send(r).unwrap()),
+        )
+        .unwrap();
+    assert!(
+        matches!(results.recv().unwrap(), Err(CompileError::Link(e)) if e.label() == "nowhere")
+    );
+
+    // keep the only worker busy
+    let (started, wait_started) = channel();
+    let (release, wait_release) = channel::<()>();
+    service
+        .submit(
+            Box::new(move || {
+                started.send(()).unwrap();
+                wait_release.recv().unwrap();
+                InstBuffer::default()
+            }),
+            16,
+            Box::new(|_| {}),
+        )
+        .unwrap();
+    wait_started.recv().unwrap();
+
+    let cancelled = done.clone();
+    let ticket = service
+        .try_submit(
+            Box::new(InstBuffer::default),
+            16,
+            Box::new(move |r| cancelled.send(r).unwrap()),
+        )
+        .unwrap();
+    assert_eq!(
+        service
+            .try_submit(Box::new(InstBuffer::default), 16, Box::new(|_| {}))
+            .unwrap_err(),
+        SubmitError::QueueFull
+    );
+    ticket.cancel();
+    release.send(()).unwrap();
+    assert!(matches!(
+        results.recv().unwrap(),
+        Err(CompileError::Cancelled)
+    ));
+}
+
+#[test]
+fn jump_link_test() {
+    use crate::insts::{
+        x86_64::{
+            inst_dump_buf::{InstBuffer, JumpInst},
+            ImmByte,
+        },
+        LinkError,
+    };
+
+    // jmp rel32 back to itself, counted from the end of the instruction
+    let buf = InstBuffer::default();
+    buf.label("top".to_string());
+    buf.jump(JumpInst::from(
+        vec![0xe9, 0, 0, 0, 0],
+        ImmByte::Bit32,
+        "top".to_string(),
+    ));
+    let mut code = vec![];
+    buf.dump(0xffff_8000_0000_0000, &mut code).unwrap();
+    assert_eq!(code, [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+
+    // jmp rel8 out of reach
+    let buf = InstBuffer::default();
+    buf.jump(JumpInst::from(
+        vec![0xeb, 0],
+        ImmByte::Bit8,
+        "far".to_string(),
+    ));
+    buf.inst(vec![0x90; 200]);
+    buf.label("far".to_string());
+    assert_eq!(
+        buf.dump(0, &mut vec![]),
+        Err(LinkError::OutOfRange("far".to_string()))
+    );
+
+    // mov rax, imm64 takes the absolute address
+    let buf = InstBuffer::default();
+    let mut mov = vec![0x48, 0xb8];
+    mov.extend([0; 8]);
+    buf.jump(JumpInst::from(mov, ImmByte::Bit64, "end".to_string()));
+    buf.label("end".to_string());
+    let mut code = vec![];
+    buf.dump(0x7fff_1234_5000, &mut code).unwrap();
+    assert_eq!(
+        code,
+        [0x48, 0xb8, 0x0a, 0x50, 0x34, 0x12, 0xff, 0x7f, 0x00, 0x00]
+    );
+    assert_eq!(
+        buf.dump(u64::MAX, &mut vec![]),
+        Err(LinkError::OutOfRange("end".to_string()))
+    );
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn function_table_test() {
+    use crate::code_cache::SharedCodeCache;
+    use crate::function_table::FunctionTable;
+    use crate::page_manage::PageSize;
+    use crate::reclaim::Reclaimer;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let reclaimer = Reclaimer::new();
+    let thread = reclaimer.register();
+    let table = FunctionTable::new(4, reclaimer.clone());
+
+    // mov eax, 1; ret
+    let id = table
+        .define(cache.install(&[0xb8, 1, 0, 0, 0, 0xc3], 16))
+        .unwrap();
+    // sub rsp, 8; call [slot]; add rsp, 8; ret
+    let mut caller = vec![0x48, 0x83, 0xec, 0x08];
+    caller.extend(table.call_slot(id));
+    caller.extend([0x48, 0x83, 0xc4, 0x08, 0xc3]);
+    let caller = cache.install(&caller, 16);
+    let f = unsafe { caller.get_function::<extern "C" fn() -> u32>(0) };
+    assert_eq!(unsafe { f.call() }, 1);
+
+    // mov eax, 2; ret
+    table.replace(id, cache.install(&[0xb8, 2, 0, 0, 0, 0xc3], 16));
+    assert_eq!(unsafe { f.call() }, 2);
+    assert_eq!(
+        unsafe { table.get_function::<extern "C" fn() -> u32>(id).call() },
+        2
+    );
+
+    assert_eq!(reclaimer.pending(), 1);
+    let used = cache.stats().used_bytes;
+    thread.quiescent();
+    assert_eq!(reclaimer.try_reclaim(), 1);
+    assert_eq!(cache.stats().used_bytes, used - 6);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn patch_site_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::code_cache::SharedCodeCache;
+    use crate::insts::x86_64::inst_dump_buf::InstBuffer;
+    use crate::page_manage::PageSize;
+    use crate::patch_site::PatchHandle;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let one = cache.install(&[0xb8, 1, 0, 0, 0, 0xc3], 16);
+    let two = cache.install(&[0xb8, 2, 0, 0, 0, 0xc3], 16);
+
+    let buf = InstBuffer::default();
+    // xor eax, eax; sub rsp, 8
+    buf.inst(vec![0x31, 0xc0, 0x48, 0x83, 0xec, 0x08]);
+    let site = buf.patchable("ic".to_string(), 5);
+    assert_eq!(site.offset, 8);
+    // add rsp, 8; ret
+    buf.inst(vec![0x48, 0x83, 0xc4, 0x08, 0xc3]);
+    assert_eq!(buf.patch_site("ic"), Some(site));
+    let mut code = vec![];
+    buf.dump(0, &mut code).unwrap();
+
+    let code = cache.install(&code, 16);
+    let f = unsafe { code.get_function::<extern "C" fn() -> u32>(0) };
+    let ic = PatchHandle::from_shared_code(&code, site).unwrap().unwrap();
+    assert_eq!(unsafe { f.call() }, 0);
+
+    ic.rewrite_call(one.get_ptr()).unwrap();
+    assert_eq!(ic.read()[0], 0xe8);
+    assert_eq!(unsafe { f.call() }, 1);
+
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                let r = unsafe { f.call() };
+                assert!(r == 1 || r == 2);
+            }
+        });
+        for i in 0..10000 {
+            let target = if i % 2 == 0 { &two } else { &one };
+            ic.rewrite_call(target.get_ptr()).unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+    });
+    assert_eq!(unsafe { f.call() }, 1);
+
+    ic.reset();
+    assert_eq!(unsafe { f.call() }, 0);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn patch_site_boundary_test() {
+    use crate::code_cache::SharedCodeCache;
+    use crate::insts::x86_64::{
+        inst_dump_buf::{InstBuffer, PatchSite},
+        inst_list::*,
+    };
+    use crate::page_manage::PageSize;
+    use crate::patch_site::PatchHandle;
+
+    let cache = SharedCodeCache::new(PageSize::from_system(), 1);
+    let one = cache.install(&[0xb8, 1, 0, 0, 0, 0xc3], 16);
+
+    let buf = InstBuffer::default();
+    // xor eax, eax
+    buf.inst(vec![0x31, 0xc0]);
+    // 8 bytes from offset 2 cross the word, the site starts at 8
+    let site = buf.patchable("ic".to_string(), 8);
+    assert_eq!(site.offset, 8);
+    // ret
+    buf.inst(vec![0xc3]);
+    let mut code = vec![];
+    buf.dump(0, &mut code).unwrap();
+    let nops = [nop5(), nop3()].concat();
+    assert_eq!(&code[8..16], &nops[..]);
+
+    let code = cache.install(&code, 16);
+    let f = unsafe { code.get_function::<extern "C" fn() -> u32>(0) };
+    let ic = PatchHandle::from_shared_code(&code, site).unwrap().unwrap();
+    assert_eq!(unsafe { f.call() }, 0);
+
+    // returning from the call lands after 5 bytes, the padding starts there
+    ic.rewrite_call(one.get_ptr()).unwrap();
+    assert_eq!(ic.read()[0], 0xe8);
+    assert_eq!(&ic.read()[5..], &nop3()[..]);
+    assert_eq!(unsafe { f.call() }, 1);
+
+    ic.reset();
+    assert_eq!(ic.read(), nops);
+    assert_eq!(unsafe { f.call() }, 0);
+
+    // short code is padded up to the boundary
+    ic.rewrite(&nop2());
+    assert_eq!(ic.read(), [nop2(), nop3(), nop3()].concat());
+    assert_eq!(unsafe { f.call() }, 0);
+
+    assert!(std::panic::catch_unwind(|| ic.rewrite(&nop6())).is_err());
+    assert_eq!(ic.read(), [nop2(), nop3(), nop3()].concat());
+
+    let past_end = PatchSite { offset: 17, len: 1 };
+    assert!(PatchHandle::from_shared_code(&code, past_end).is_err());
+    let wrapping = PatchSite {
+        offset: u32::MAX,
+        len: 8,
+    };
+    assert!(PatchHandle::from_shared_code(&code, wrapping).is_err());
+}
+
+#[test]
+fn code_sink_test() {
+    use crate::insts::x86_64::Op1;
+    use crate::insts::x86_64::{inst_dump_buf::InstBuffer, inst_list::*, registers::Register64};
+    use crate::insts::{CodeSink, FixedBuf};
+
+    let expected = [
+        mov(false, true, Op1::Direct(Register64::Rax), Register64::Rcx),
+        add_imm8(false, true, Op1::DeRef(Register64::Rdx, 8), 1),
+        near_ret(),
+    ]
+    .concat();
+
+    let mut v = vec![];
+    mov_to(
+        &mut v,
+        false,
+        true,
+        Op1::Direct(Register64::Rax),
+        Register64::Rcx,
+    )
+    .unwrap();
+    add_imm8_to(&mut v, false, true, Op1::DeRef(Register64::Rdx, 8), 1).unwrap();
+    near_ret_to(&mut v).unwrap();
+    assert_eq!(v, expected);
+
+    let mut storage = [0u8; 16];
+    let mut fixed = FixedBuf::new(&mut storage);
+    mov_to(
+        &mut fixed,
+        false,
+        true,
+        Op1::Direct(Register64::Rax),
+        Register64::Rcx,
+    )
+    .unwrap();
+    add_imm8_to(&mut fixed, false, true, Op1::DeRef(Register64::Rdx, 8), 1).unwrap();
+    near_ret_to(&mut fixed).unwrap();
+    assert!(!fixed.overflowed());
+    assert_eq!(fixed.as_slice(), &expected[..]);
+
+    let mut small = [0u8; 2];
+    let mut fixed = FixedBuf::new(&mut small);
+    mov_to(
+        &mut fixed,
+        false,
+        true,
+        Op1::Direct(Register64::Rax),
+        Register64::Rcx,
+    )
+    .unwrap();
+    assert!(fixed.overflowed());
+    assert_eq!(fixed.position(), 3);
+
+    let mut buf = InstBuffer::default();
+    mov_to(
+        &mut buf,
+        false,
+        true,
+        Op1::Direct(Register64::Rax),
+        Register64::Rcx,
+    )
+    .unwrap();
+    buf.label("ret".to_string());
+    add_imm8_to(&mut buf, false, true, Op1::DeRef(Register64::Rdx, 8), 1).unwrap();
+    near_ret_to(&mut buf).unwrap();
+    assert_eq!(buf.label_buf.borrow()["ret"], 3);
+    let mut dumped = vec![];
+    buf.dump(0, &mut dumped).unwrap();
+    assert_eq!(dumped, expected);
+}
+
+#[test]
+fn encode_error_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64, ScaledIndex},
+        EncodeError, Op1, Operand,
+    };
+    use crate::insts::CodeSink;
+
+    let mut v = vec![];
+    let far = Op1::ScaleBase(Register64::Rax, Register64::Rsp, ScaledIndex::Id, 0);
+    assert_eq!(
+        mov_to(&mut v, false, true, far, Register64::Rcx),
+        Err(EncodeError::InvalidIndex {
+            operand: Operand::Op1
+        })
+    );
+    // nothing is written on error
+    assert_eq!(v.position(), 0);
+    assert!(mov_to(
+        &mut v,
+        false,
+        true,
+        Op1::ScaleBase(Register64::Rax, Register64::R12, ScaledIndex::Id, 0),
+        Register64::Rcx
+    )
+    .is_ok());
+
+    assert_eq!(
+        Register64::try_from_u8(16),
+        Err(EncodeError::InvalidRegister { value: 16, max: 15 })
+    );
+    assert_eq!(Register64::try_from_u8(15), Ok(Register64::R15));
+    assert!(std::panic::catch_unwind(|| mov(false, true, far, Register64::Rcx)).is_err());
+}
+
+#[test]
+fn operand_size_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64, Register8},
+        EncodeError, Op1, Operand, OperandSize,
+    };
+
+    let rax = Op1::Direct(Register64::Rax);
+    assert_eq!(
+        mov(false, OperandSize::Bit8, rax, Register64::Rcx),
+        [0x88, 0xc8]
+    );
+    assert_eq!(
+        mov(false, OperandSize::Bit16, rax, Register64::Rcx),
+        [0x66, 0x89, 0xc8]
+    );
+    assert_eq!(
+        mov(false, OperandSize::Bit32, rax, Register64::Rcx),
+        [0x89, 0xc8]
+    );
+    assert_eq!(
+        mov(false, OperandSize::Bit64, rax, Register64::Rcx),
+        [0x48, 0x89, 0xc8]
+    );
+    // `long_mode` still works
+    assert_eq!(mov(false, true, rax, Register64::Rcx), [0x48, 0x89, 0xc8]);
+
+    // mov sil, dil / mov r8b, al / mov al, r8b
+    assert_eq!(
+        mov(
+            false,
+            OperandSize::Bit8,
+            Op1::Direct(Register64::Rsi),
+            Register64::Rdi
+        ),
+        [0x40, 0x88, 0xfe]
+    );
+    assert_eq!(
+        mov(
+            false,
+            OperandSize::Bit8,
+            Op1::Direct(Register64::R8),
+            Register64::Rax
+        ),
+        [0x41, 0x88, 0xc0]
+    );
+    assert_eq!(
+        mov(false, OperandSize::Bit8, rax, Register64::R8),
+        [0x44, 0x88, 0xc0]
+    );
+    // mov ah, cl
+    assert_eq!(
+        mov(
+            false,
+            OperandSize::Bit8,
+            Op1::Byte(Register8::AH),
+            Register64::Rcx
+        ),
+        [0x88, 0xcc]
+    );
+    // mov byte [rdi], al: a memory base is never a byte register
+    assert_eq!(
+        mov(
+            false,
+            OperandSize::Bit8,
+            Op1::DeRef(Register64::Rdi, 0),
+            Register64::Rax
+        ),
+        [0x88, 0x07]
+    );
+
+    let mut v = vec![];
+    assert_eq!(
+        mov_to(
+            &mut v,
+            false,
+            OperandSize::Bit8,
+            Op1::Byte(Register8::AH),
+            Register64::Rsi
+        ),
+        Err(EncodeError::RexConflict {
+            operand: Operand::Op1,
+            reg: Register8::AH
+        })
+    );
+    assert_eq!(
+        mov_to(&mut v, false, OperandSize::Bit8, rax, Register8::BH),
+        Ok(())
+    );
+    assert_eq!(
+        mov_to(
+            &mut v,
+            false,
+            OperandSize::Bit8,
+            Op1::Direct(Register64::R8),
+            Register8::BH
+        ),
+        Err(EncodeError::RexConflict {
+            operand: Operand::Op2,
+            reg: Register8::BH
+        })
+    );
+    assert_eq!(
+        mov_to(&mut v, false, OperandSize::Bit32, rax, Register8::AH),
+        Err(EncodeError::SizeMismatch {
+            operand: Operand::Op2,
+            size: OperandSize::Bit32
+        })
+    );
+    assert_eq!(v, [0x88, 0xf8]);
+
+    // add word [rax], 0x1234 / add byte [rax], 0x7f / inc rax
+    let mem = Op1::DeRef(Register64::Rax, 0);
+    assert_eq!(
+        add_imm32(false, OperandSize::Bit16, mem, 0x1234),
+        [0x66, 0x81, 0x00, 0x34, 0x12]
+    );
+    assert_eq!(
+        add_imm32(false, OperandSize::Bit8, mem, 0x7f),
+        [0x80, 0x00, 0x7f]
+    );
+    assert_eq!(
+        add_imm8(false, OperandSize::Bit8, mem, 0x7f),
+        [0x80, 0x00, 0x7f]
+    );
+    assert_eq!(
+        add_imm32_to(&mut v, false, OperandSize::Bit8, mem, 0x100),
+        Err(EncodeError::ImmOutOfRange {
+            imm: 0x100,
+            size: OperandSize::Bit8
+        })
+    );
+    assert_eq!(inc(false, OperandSize::Bit64, rax), [0x48, 0xff, 0xc0]);
+}
+
+#[test]
+fn sized_digit_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64, Register8},
+        EncodeError, Op1, OperandSize,
+    };
+    use Register64::*;
+
+    let (b, w, d, q) = (
+        OperandSize::Bit8,
+        OperandSize::Bit16,
+        OperandSize::Bit32,
+        OperandSize::Bit64,
+    );
+    // cmp
+    assert_eq!(cmp(b, Op1::Direct(Rsi), Rax), [0x40, 0x38, 0xc6]);
+    assert_eq!(cmp(q, Op1::Direct(Rcx), R8), [0x4c, 0x39, 0xc1]);
+    assert_eq!(
+        cmp_rev(w, Op1::DeRef(Rbx, 8), Rdx),
+        [0x66, 0x3b, 0x53, 0x08]
+    );
+    assert_eq!(
+        cmp_imm(w, Op1::Direct(Rax), 0x1234),
+        [0x66, 0x81, 0xf8, 0x34, 0x12]
+    );
+    assert_eq!(cmp_imm8(q, Op1::Direct(R9), 0xff), [0x49, 0x83, 0xf9, 0xff]);
+    assert_eq!(cmp_first_reg_and_imm(b, 5), [0x3c, 0x05]);
+    // arithmetic, the /digit is in ModRM.reg
+    assert_eq!(
+        sub_imm(false, b, Op1::Direct(Rcx), 0x7f),
+        [0x80, 0xe9, 0x7f]
+    );
+    assert!(matches!(
+        sub_imm_to(&mut vec![], false, b, Op1::Direct(Rcx), 0x100),
+        Err(EncodeError::ImmOutOfRange { imm: 0x100, .. })
+    ));
+    assert_eq!(neg(false, q, Op1::Direct(Rax)), [0x48, 0xf7, 0xd8]);
+    assert_eq!(dec(false, w, Op1::DeRef(Rdi, 0)), [0x66, 0xff, 0x0f]);
+    assert_eq!(
+        idiv_first_reg(false, q, Op1::Direct(Rcx)),
+        [0x48, 0xf7, 0xf9]
+    );
+    assert_eq!(div_byte_first_reg(false, Op1::Direct(Rbx)), [0xf6, 0xf3]);
+    assert_eq!(
+        mul_first_reg(false, d, Op1::Direct(R10)),
+        [0x41, 0xf7, 0xe2]
+    );
+    assert_eq!(
+        imul_reg_and_imm32(false, w, Op1::Direct(Rcx), Rax, 0x1234),
+        [0x66, 0x69, 0xc1, 0x34, 0x12]
+    );
+    assert!(matches!(
+        imul_reg_to(&mut vec![], false, b, Op1::Direct(Rcx), Rax),
+        Err(EncodeError::UnsupportedSize { .. })
+    ));
+    assert_eq!(
+        lea(false, q, Op1::DeRef(Rsp, 16), Rax),
+        [0x48, 0x8d, 0x44, 0x24, 0x10]
+    );
+    // logic
+    assert_eq!(
+        test_imm(q, Op1::Direct(Rax), 0x8000_0000),
+        [0x48, 0xf7, 0xc0, 0x00, 0x00, 0x00, 0x80]
+    );
+    assert_eq!(test_imm8(Op1::Direct(Rcx), 1), [0xf6, 0xc1, 0x01]);
+    assert_eq!(
+        and_reg_imm8(q, Op1::Direct(Rsp), 0xf0),
+        [0x48, 0x83, 0xe4, 0xf0]
+    );
+    assert_eq!(or_reg_imm32(b, Op1::Direct(Rdx), 1), [0x80, 0xca, 0x01]);
+    // moves and sign extension
+    assert_eq!(sign_extend2(q), [0x48, 0x99]);
+    assert_eq!(mov_imm_into_reg(false, b, Rsi, 1), [0x40, 0xb6, 0x01]);
+    assert_eq!(
+        imm_sign_extend_into_reg(false, q, Op1::Direct(Rax), 0xffff_ffff),
+        [0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]
+    );
+    assert_eq!(
+        mov_zero_extend_bit8(false, d, Op1::Direct(Rcx), Rax),
+        [0x0f, 0xb6, 0xc1]
+    );
+    // the source of movzx/movsx is a byte: sil needs REX, ah cannot have it
+    assert_eq!(
+        mov_zero_extend_bit8(false, d, Op1::Direct(Rsi), Rax),
+        [0x40, 0x0f, 0xb6, 0xc6]
+    );
+    assert_eq!(
+        mov_sign_extend_bit8(false, q, Op1::Byte(Register8::CL), Rax),
+        [0x48, 0x0f, 0xbe, 0xc1]
+    );
+    assert_eq!(
+        mov_zero_extend_bit8(false, d, Op1::Byte(Register8::AH), Rax),
+        [0x0f, 0xb6, 0xc4]
+    );
+    assert!(matches!(
+        mov_zero_extend_bit8_to(&mut vec![], false, d, Op1::Byte(Register8::AH), R8),
+        Err(EncodeError::RexConflict { .. })
+    ));
+}
+
+#[test]
+fn addressing_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64::*, ScaledIndex},
+        Op1,
+    };
+
+    // mov [rbp-8], rax
+    assert_eq!(
+        mov(false, true, Op1::DeRef(Rbp, -8), Rax),
+        [0x48, 0x89, 0x45, 0xf8]
+    );
+    // mov [rbp], rax / mov [r13], rax: no mode without displacement
+    assert_eq!(
+        mov(false, true, Op1::DeRef(Rbp, 0), Rax),
+        [0x48, 0x89, 0x45, 0x00]
+    );
+    assert_eq!(
+        mov(false, true, Op1::DeRef(R13, 0), Rax),
+        [0x49, 0x89, 0x45, 0x00]
+    );
+    // mov [rsp], rax / mov [r12+0x100], rax: need a SIB
+    assert_eq!(
+        mov(false, true, Op1::DeRef(Rsp, 0), Rax),
+        [0x48, 0x89, 0x04, 0x24]
+    );
+    assert_eq!(
+        mov(false, true, Op1::DeRef(R12, 0x100), Rax),
+        [0x49, 0x89, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]
+    );
+    // mov [rax-0x81], rax: below the disp8 range
+    assert_eq!(
+        mov(false, true, Op1::DeRef(Rax, -0x81), Rax),
+        [0x48, 0x89, 0x80, 0x7f, 0xff, 0xff, 0xff]
+    );
+    // mov [r13+r9*8], rax
+    assert_eq!(
+        mov(
+            false,
+            true,
+            Op1::ScaleBase(R13, R9, ScaledIndex::Mul8, 0),
+            Rax
+        ),
+        [0x4b, 0x89, 0x44, 0xcd, 0x00]
+    );
+    // mov [rcx*4+0x10], rax
+    assert_eq!(
+        mov(false, true, Op1::Index(Rcx, ScaledIndex::Mul4, 0x10), Rax),
+        [0x48, 0x89, 0x04, 0x8d, 0x10, 0x00, 0x00, 0x00]
+    );
+    // mov [0x1000], rax
+    assert_eq!(
+        mov(false, true, Op1::Abs(0x1000), Rax),
+        [0x48, 0x89, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn rip_relative_test() {
+    use crate::insts::x86_64::{
+        inst_dump_buf::{InstBuffer, RipInst},
+        inst_list::*,
+        registers::Register64,
+        ImmByte, Op1,
+    };
+
+    // mov rax, [rip+0x10]
+    assert_eq!(
+        mov_rev(false, true, Op1::Rip(0x10), Register64::Rax),
+        [0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]
+    );
+
+    // mov rax, [rip+k]; ret; k: dq
+    let buf = InstBuffer::default();
+    buf.rip(RipInst::from(
+        mov_rev(false, true, Op1::Rip(0), Register64::Rax),
+        None,
+        "k".to_string(),
+    ));
+    buf.inst(near_ret());
+    buf.label("k".to_string());
+    buf.inst(0x1234_5678_9abc_def0u64.to_le_bytes().to_vec());
+    let mut code = vec![];
+    buf.dump(0x1000, &mut code).unwrap();
+    assert_eq!(&code[3..7], &1i32.to_le_bytes());
+
+    // the displacement is measured from the end, after the immediate
+    let buf = InstBuffer::default();
+    buf.label("back".to_string());
+    buf.rip(RipInst::from(
+        add_imm32(false, true, Op1::Rip(0), 1),
+        Some(ImmByte::Bit32),
+        "back".to_string(),
+    ));
+    let mut add = vec![];
+    buf.dump(0, &mut add).unwrap();
+    assert_eq!(
+        add,
+        [0x48, 0x81, 0x05, 0xf5, 0xff, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00]
+    );
+
+    let missing = InstBuffer::default();
+    missing.rip(RipInst::from(
+        mov_rev(false, true, Op1::Rip(0), Register64::Rax),
+        None,
+        "nowhere".to_string(),
+    ));
+    assert!(missing.dump(0, &mut vec![]).is_err());
+    missing.label("nowhere".to_string());
+    assert!(missing.dump(0, &mut vec![]).is_ok());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::page_manage::{PageHandle, PageSize};
+
+        let page = PageHandle::from(PageSize::from_system(), &code);
+        let f = unsafe { page.get_function::<extern "C" fn() -> u64>(0) };
+        assert_eq!(unsafe { f.call() }, 0x1234_5678_9abc_def0);
+    }
+}
+
+#[test]
+fn cross_host_encode_test() {
+    // nothing here runs the code, it must pass on any host
+    use crate::insts::x86_64::{
+        inst_dump_buf::{InstBuffer, JumpInst},
+        inst_list::*,
+        registers::Register64,
+        ImmByte,
+    };
+
+    // B8+r imm, no ModRM
+    assert_eq!(
+        mov_imm_into_reg(false, true, Register64::Rax, 0x0102_0304_0506_0708),
+        [0x48, 0xb8, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+    );
+    assert_eq!(
+        mov_imm_into_reg(false, true, Register64::R9, 0x0102_0304_0506_0708),
+        [0x49, 0xb9, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+    );
+    assert_eq!(
+        mov_imm_into_reg(false, false, Register64::Rax, 0x0102_0304),
+        [0xb8, 0x04, 0x03, 0x02, 0x01]
+    );
+    assert_eq!(
+        mov_imm_into_reg(false, false, Register64::R10, 0x0102_0304),
+        [0x41, 0xba, 0x04, 0x03, 0x02, 0x01]
+    );
+
+    // jmp rel32 over a nop, the same bytes at any base address
+    let buf = InstBuffer::default();
+    buf.jump(JumpInst::from(
+        vec![0xe9, 0, 0, 0, 0],
+        ImmByte::Bit32,
+        "end".to_string(),
+    ));
+    buf.inst(nop1());
+    buf.label("end".to_string());
+    let mut code = vec![];
+    buf.dump(0x0102_0300, &mut code).unwrap();
+    assert_eq!(code, [0xe9, 0x01, 0x00, 0x00, 0x00, 0x90]);
+}
+
+#[test]
+fn sse_rex_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64, RegisterXmm::*, ScaledIndex},
+        Op1,
+    };
+
+    // addsd xmm9, xmm1
+    assert_eq!(addsd(Op1::from(XMM1), XMM9), [0xf2, 0x44, 0x0f, 0x58, 0xc9]);
+    // movss xmm0, [r12]
+    assert_eq!(
+        movss(Op1::DeRef(Register64::R12, 0), XMM0),
+        [0xf3, 0x41, 0x0f, 0x10, 0x04, 0x24]
+    );
+    // movsd [rax+8], xmm15
+    assert_eq!(
+        movsd_rev(Op1::DeRef(Register64::Rax, 8), XMM15),
+        [0xf2, 0x44, 0x0f, 0x11, 0x78, 0x08]
+    );
+    // movupd xmm8, xmm10
+    assert_eq!(
+        movupd(Op1::from(XMM10), XMM8),
+        [0x66, 0x45, 0x0f, 0x10, 0xc2]
+    );
+    // movupd [rdi+16], xmm9, the store form
+    assert_eq!(
+        movupd_rev(Op1::DeRef(Register64::Rdi, 16), XMM9),
+        [0x66, 0x44, 0x0f, 0x11, 0x4f, 0x10]
+    );
+    // cmpeqsd xmm3, [r13+r14*2]
+    assert_eq!(
+        cmpsd(
+            Op1::ScaleBase(Register64::R13, Register64::R14, ScaledIndex::Mul2, 0),
+            XMM3,
+            FcmpOp::Eq
+        ),
+        [0xf2, 0x43, 0x0f, 0xc2, 0x5c, 0x75, 0x00, 0x00]
+    );
+    // no REX for the low registers
+    assert_eq!(addss(Op1::from(XMM1), XMM0), [0xf3, 0x0f, 0x58, 0xc1]);
+}
+
+#[test]
+fn shift_test() {
+    use crate::insts::x86_64::{
+        inst_list::*,
+        registers::{Register64::*, Register8},
+        EncodeError, Op1,
+        OperandSize::*,
+    };
+
+    assert_eq!(shl(Bit64, Op1::Direct(Rax)), [0x48, 0xd1, 0xe0]);
+    assert_eq!(shl_cl(Bit8, Op1::DeRef(Rdi, 0)), [0xd2, 0x27]);
+    assert_eq!(
+        sar_imm8(Bit32, Op1::Direct(R9), 3),
+        [0x41, 0xc1, 0xf9, 0x03]
+    );
+    assert_eq!(rol(Bit8, Op1::Direct(Rsi)), [0x40, 0xd0, 0xc6]);
+    assert_eq!(
+        rcr_imm8(Bit16, Op1::DeRef(Rax, 8), 2),
+        [0x66, 0xc1, 0x58, 0x08, 0x02]
+    );
+    assert_eq!(ror_cl(Bit8, Op1::Byte(Register8::AH)), [0xd2, 0xcc]);
+    assert_eq!(rcl(false, Op1::Direct(Rax)), [0xd1, 0xd0]);
+    assert_eq!(shr_cl(false, Op1::Direct(Rax)), [0xd3, 0xe8]);
+
+    assert_eq!(
+        shld_imm8(Bit64, Op1::Direct(Rax), Rcx, 4),
+        [0x48, 0x0f, 0xa4, 0xc8, 0x04]
+    );
+    assert_eq!(
+        shrd_cl(Bit32, Op1::DeRef(Rbx, 0), R8),
+        [0x44, 0x0f, 0xad, 0x03]
+    );
+    assert_eq!(
+        shld_cl_to(&mut vec![], Bit8, Op1::Direct(Rax), Rcx),
+        Err(EncodeError::UnsupportedSize { size: Bit8 })
+    );
 }
@@ -1,10 +1,68 @@
 #[cfg(unix)]
 use std::ffi::c_void;
+use std::sync::Mutex;
 #[cfg(windows)]
 use winapi::ctypes::c_void;
 
+use lazy_static::lazy_static;
+
+use crate::jit_function::{JitFn, JitFunction};
+
 // use crate::insts::x86_64::inst_dump_buf::InstBuffer;
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PageError {
+    /// querying the system page size failed
+    PageSize(i32),
+    /// mmap / VirtualAlloc / shared memory setup failed
+    Alloc(i32),
+    /// mprotect / VirtualProtect failed
+    Protect(i32),
+    /// the range is outside the installed code
+    OutOfRange { offset: usize, len: usize },
+    /// no free pages within rel32 range of the target
+    OutOfReach,
+    /// the sections do not fit the `ModuleLayout`
+    Layout,
+}
+
+impl PageError {
+    /// the OS error code (errno / GetLastError) if the error comes from the OS.
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            PageError::PageSize(e) | PageError::Alloc(e) | PageError::Protect(e) => Some(e),
+            PageError::OutOfRange { .. } | PageError::OutOfReach | PageError::Layout => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            PageError::PageSize(e) => write!(f, "get page size failed (os error {})", e),
+            PageError::Alloc(e) => write!(f, "page allocation failed (os error {})", e),
+            PageError::Protect(e) => write!(f, "page protection failed (os error {})", e),
+            PageError::OutOfRange { offset, len } => {
+                write!(
+                    f,
+                    "range of {} bytes at offset {} out of installed code",
+                    len, offset
+                )
+            }
+            PageError::OutOfReach => write!(f, "no free pages within rel32 range"),
+            PageError::Layout => write!(f, "sections do not fit the module layout"),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+#[cfg(windows)]
+#[inline]
+fn last_os_error() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct PageSize(pub usize);
 
@@ -15,151 +73,712 @@ impl From<usize> for PageSize {
 }
 
 impl PageSize {
-    #[cfg(unix)]
     #[inline]
     pub fn from_system() -> Self {
+        Self::try_from_system().unwrap()
+    }
+
+    #[cfg(unix)]
+    #[inline]
+    pub fn try_from_system() -> Result<Self, PageError> {
         use nix::unistd::{sysconf, SysconfVar};
 
-        let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize;
-        Self(page_size)
+        match sysconf(SysconfVar::PAGE_SIZE) {
+            Ok(Some(page_size)) => Ok(Self(page_size as usize)),
+            Ok(None) => Err(PageError::PageSize(0)),
+            Err(e) => Err(PageError::PageSize(e as i32)),
+        }
     }
 
     #[cfg(windows)]
     #[inline]
-    pub fn from_system() -> Self {
-        use std::alloc::{alloc, dealloc, Layout};
+    pub fn try_from_system() -> Result<Self, PageError> {
         use winapi::um::sysinfoapi;
 
-        let size = std::mem::size_of::<sysinfoapi::SYSTEM_INFO>();
-        let layout = Layout::from_size_align(size, 8).unwrap();
-        let sysinfo = unsafe { alloc(layout) } as *mut sysinfoapi::SYSTEM_INFO;
-
         let page_size = unsafe {
-            sysinfoapi::GetSystemInfo(sysinfo);
-            (*sysinfo).dwPageSize as usize
+            let mut sysinfo: sysinfoapi::SYSTEM_INFO = std::mem::zeroed();
+            sysinfoapi::GetSystemInfo(&mut sysinfo);
+            sysinfo.dwPageSize as usize
         };
+        if page_size == 0 {
+            return Err(PageError::PageSize(last_os_error()));
+        }
+        Ok(Self(page_size))
+    }
+}
 
-        unsafe {
-            dealloc(sysinfo as *mut u8, layout);
+impl PageSize {
+    /// panics when the result overflows `usize`, see `checked_round_up`.
+    #[inline]
+    pub fn round_up(&self, size: usize) -> usize {
+        self.checked_round_up(size)
+            .expect("PageSize::round_up: size overflows usize")
+    }
+
+    /// `None` when the result overflows `usize`.
+    #[inline]
+    pub fn checked_round_up(&self, size: usize) -> Option<usize> {
+        let size_mod = size % self.0;
+        if size_mod == 0 {
+            Some(size)
+        } else {
+            size.checked_add(self.0 - size_mod)
         }
-        Self(page_size)
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// the size of the pages for `size` bytes and of the whole mapping with a guard area on each side.
+fn alloc_sizes(
+    size: usize,
+    page_size: PageSize,
+    guard: usize,
+) -> Result<(usize, usize), PageError> {
+    page_size
+        .checked_round_up(size)
+        .and_then(|alloc_size| {
+            let total = guard.checked_mul(2)?.checked_add(alloc_size)?;
+            Some((alloc_size, total))
+        })
+        .ok_or_else(size_overflow)
+}
+
+/// a size that does not fit the address space, reported like a failed allocation.
+fn size_overflow() -> PageError {
+    #[cfg(unix)]
+    let e = nix::errno::Errno::ENOMEM as i32;
+    #[cfg(windows)]
+    let e = winapi::shared::winerror::ERROR_NOT_ENOUGH_MEMORY as i32;
+    PageError::Alloc(e)
+}
+
+/// `ptr` is the executable view of the pages.
+/// `alias` is the writable view of the same physical pages when the handle is dual mapped (W^X),
+/// `None` when the handle is a single mapping flipped from RW to RX by `make_page_executable`.
+/// `guard` is the size of the PROT_NONE guard area on each side of `ptr..ptr + cap`, 0 if unguarded.
+///
+/// The handle owns the mappings and unmaps them on drop, it cannot be cloned.
+#[derive(Debug, Eq, PartialEq)]
 pub struct PageHandle {
-    pub ptr: *mut u8,
-    pub len: usize,
+    ptr: *mut u8,
+    alias: Option<*mut u8>,
+    len: usize,
+    cap: usize,
+    guard: usize,
+}
+
+// the handle owns its mappings, nothing in it is tied to the creating thread.
+// it is not `Sync`, `patch` on a shared handle would race with itself.
+unsafe impl Send for PageHandle {}
+
+/// Access rights for `PageHandle::try_protect`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Protection {
+    ReadOnly,
+    ReadWrite,
+    ReadExecute,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GuardSide {
+    /// the address is before the start of the region (underflow)
+    Below,
+    /// the address is after the end of the region (runaway code, overflow)
+    Above,
+}
+
+/// A faulting address inside the guard area of a known region.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GuardPageHit {
+    /// executable start of the region
+    pub ptr: usize,
     pub cap: usize,
+    pub side: GuardSide,
+}
+
+lazy_static! {
+    /// (ptr, cap, guard) of all alive guarded handles
+    static ref GUARDED_REGIONS: Mutex<Vec<(usize, usize, usize)>> = Mutex::new(vec![]);
+}
+
+fn guard_side(ptr: usize, cap: usize, guard: usize, addr: usize) -> Option<GuardSide> {
+    if addr < ptr && addr >= ptr.wrapping_sub(guard) {
+        Some(GuardSide::Below)
+    } else if addr >= ptr + cap && addr < ptr + cap + guard {
+        Some(GuardSide::Above)
+    } else {
+        None
+    }
+}
+
+/// Whether `addr` (e.g. the fault address of a SIGSEGV) is inside a guard page
+/// of any alive guarded `PageHandle`.
+pub fn find_guard_page(addr: *const u8) -> Option<GuardPageHit> {
+    let addr = addr as usize;
+    GUARDED_REGIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|&(ptr, cap, guard)| {
+            guard_side(ptr, cap, guard, addr).map(|side| GuardPageHit { ptr, cap, side })
+        })
 }
 
 impl PageHandle {
+    #[inline]
+    pub fn new(size: usize, page_size: PageSize) -> Self {
+        Self::try_new(size, page_size).unwrap()
+    }
+
+    #[inline]
+    pub fn try_new(size: usize, page_size: PageSize) -> Result<Self, PageError> {
+        Self::try_new_at(size, page_size, std::ptr::null_mut(), 0)
+    }
+
+    #[inline]
+    pub fn new_guarded(size: usize, page_size: PageSize) -> Self {
+        Self::try_new_guarded(size, page_size).unwrap()
+    }
+
+    /// Surround the pages with one PROT_NONE guard page on each side,
+    /// code running off either end faults immediately, see `find_guard_page`.
+    #[inline]
+    pub fn try_new_guarded(size: usize, page_size: PageSize) -> Result<Self, PageError> {
+        Self::try_new_at(size, page_size, std::ptr::null_mut(), page_size.0)
+            .map(Self::register_guard)
+    }
+
+    #[inline]
+    pub fn new_dual(size: usize, page_size: PageSize) -> Self {
+        Self::try_new_dual(size, page_size).unwrap()
+    }
+
+    /// Map the same physical pages twice, a RW view (`alias`) and a RX view (`ptr`),
+    /// no page is ever writable and executable at once.
+    #[inline]
+    pub fn try_new_dual(size: usize, page_size: PageSize) -> Result<Self, PageError> {
+        Self::try_new_dual_at(size, page_size, std::ptr::null_mut(), 0)
+    }
+
+    #[inline]
+    pub fn new_dual_guarded(size: usize, page_size: PageSize) -> Self {
+        Self::try_new_dual_guarded(size, page_size).unwrap()
+    }
+
+    /// dual mapped version of `try_new_guarded`, the guard pages surround the RX view.
+    #[inline]
+    pub fn try_new_dual_guarded(size: usize, page_size: PageSize) -> Result<Self, PageError> {
+        Self::try_new_dual_at(size, page_size, std::ptr::null_mut(), page_size.0)
+            .map(Self::register_guard)
+    }
+
+    fn register_guard(self) -> Self {
+        GUARDED_REGIONS
+            .lock()
+            .unwrap()
+            .push((self.ptr as usize, self.cap, self.guard));
+        self
+    }
+
+    fn unregister_guard(&self) {
+        let key = (self.ptr as usize, self.cap, self.guard);
+        let mut regions = GUARDED_REGIONS.lock().unwrap();
+        if let Some(i) = regions.iter().position(|r| *r == key) {
+            regions.swap_remove(i);
+        }
+    }
+
+    /// whether `addr` is inside the guard pages of this handle.
+    #[inline]
+    pub fn is_guard_addr(&self, addr: *const u8) -> bool {
+        guard_side(self.ptr as usize, self.cap, self.guard, addr as usize).is_some()
+    }
+
+    /// Allocate pages that are completely within rel32 (±2GiB) range of `target`,
+    /// so x86_64 `call rel32`/`jmp rel32` can reach `target` from them and back.
+    /// `PageError::OutOfReach` means the caller has to fall back to an indirect sequence.
+    #[inline]
+    pub fn try_new_near(
+        size: usize,
+        page_size: PageSize,
+        target: *const u8,
+    ) -> Result<Self, PageError> {
+        Self::try_near(size, page_size, target, |size, page_size, hint| {
+            Self::try_new_at(size, page_size, hint, 0)
+        })
+    }
+
+    /// dual mapped version of `try_new_near`, only the RX view is placed near `target`.
+    #[inline]
+    pub fn try_new_dual_near(
+        size: usize,
+        page_size: PageSize,
+        target: *const u8,
+    ) -> Result<Self, PageError> {
+        Self::try_near(size, page_size, target, |size, page_size, hint| {
+            Self::try_new_dual_at(size, page_size, hint, 0)
+        })
+    }
+
+    fn try_near(
+        size: usize,
+        page_size: PageSize,
+        target: *const u8,
+        alloc: impl Fn(usize, PageSize, *mut u8) -> Result<Self, PageError>,
+    ) -> Result<Self, PageError> {
+        // every candidate would fail the same way and be skipped as an address in use
+        alloc_sizes(size, page_size, 0)?;
+        for hint in near_candidates(target as usize) {
+            match alloc(size, page_size, hint as *mut u8) {
+                Ok(r) if r.is_near(target) => return Ok(r),
+                // placed somewhere else, unmapped by drop
+                Ok(_) => {}
+                // the address is in use
+                Err(PageError::Alloc(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(PageError::OutOfReach)
+    }
+
+    /// whether every byte of the pages is within rel32 range of `target`.
+    #[inline]
+    pub fn is_near(&self, target: *const u8) -> bool {
+        let start = self.ptr as usize;
+        rel32_reachable(target as usize, start)
+            && rel32_reachable(target as usize, start + self.cap)
+    }
+
     #[inline]
     pub fn from(page_size: PageSize, src: &[u8]) -> Self {
-        let r = Self::new(src.len(), page_size);
+        Self::try_from(page_size, src).unwrap()
+    }
+
+    #[inline]
+    pub fn try_from(page_size: PageSize, src: &[u8]) -> Result<Self, PageError> {
+        let r = Self::try_new(src.len(), page_size)?;
         unsafe {
             std::ptr::copy(src.as_ptr(), r.ptr, r.len);
         }
-        r.make_page_executable();
+        r.try_make_executable()?;
+        Ok(r)
+    }
+
+    /// dual mapped version of `from`, the code stays patchable through `get_write_ptr`.
+    #[inline]
+    pub fn from_dual(page_size: PageSize, src: &[u8]) -> Self {
+        Self::try_from_dual(page_size, src).unwrap()
+    }
+
+    #[inline]
+    pub fn try_from_dual(page_size: PageSize, src: &[u8]) -> Result<Self, PageError> {
+        let r = Self::try_new_dual(src.len(), page_size)?;
+        unsafe {
+            std::ptr::copy(src.as_ptr(), r.get_write_ptr().unwrap(), src.len());
+        }
+        Ok(r)
+    }
+
+    /// `from` and record the code under `name` for the profiler, see `crate::profiler`.
+    #[inline]
+    pub fn from_named(page_size: PageSize, src: &[u8], name: &str) -> Self {
+        let r = Self::from(page_size, src);
+        r.record_symbol(name, 0, r.len);
         r
     }
 
+    /// Name `offset..offset + len` of the installed code for the profiler,
+    /// no-op unless `crate::profiler` is enabled. Profiling failures are not reported.
+    pub fn record_symbol(&self, name: &str, offset: usize, len: usize) {
+        assert!(offset + len <= self.len, "record_symbol range invalid");
+        let _ = unsafe { crate::profiler::record_code(name, self.ptr.add(offset), len) };
+    }
+
+    /// Describe the installed code to gdb/lldb, unregistered when the handle is dropped.
+    #[cfg(target_pointer_width = "64")]
+    pub fn register_gdb(&self, info: &crate::gdb_jit::DebugInfo) {
+        crate::gdb_jit::register_code(self.ptr, self.len, info);
+    }
+
+    #[inline]
+    pub fn make_page_executable(&self) {
+        self.try_make_executable().unwrap()
+    }
+
+    /// store `src` at the start of the installed code, see `patch`.
     #[inline]
     pub fn store(&self, src: &[u8]) {
-        if src.len() > self.len {
-            panic!("store length invilid");
+        self.patch(0, src)
+    }
+
+    #[inline]
+    pub fn patch(&self, offset: usize, src: &[u8]) {
+        self.try_patch(offset, src).unwrap()
+    }
+
+    /// Overwrite installed code at `offset`.
+    ///
+    /// Dual mapped handles write through the RW view,
+    /// single mapped handles are made RW for the write and RX again afterwards,
+    /// so no other thread may execute the pages while they are patched.
+    /// The instruction cache is flushed where the architecture requires it.
+    pub fn try_patch(&self, offset: usize, src: &[u8]) -> Result<(), PageError> {
+        if !matches!(offset.checked_add(src.len()), Some(end) if end <= self.len) {
+            return Err(PageError::OutOfRange {
+                offset,
+                len: src.len(),
+            });
         }
         unsafe {
-            std::ptr::copy(src.as_ptr(), self.ptr, self.len);
+            if let Some(alias) = self.alias {
+                std::ptr::copy(src.as_ptr(), alias.add(offset), src.len());
+            } else {
+                self.try_make_writable()?;
+                std::ptr::copy(src.as_ptr(), self.ptr.add(offset), src.len());
+                self.try_make_executable()?;
+            }
         }
+        flush_icache(unsafe { self.ptr.add(offset) }, src.len());
+        Ok(())
     }
 
     #[inline]
     pub fn get_ptr(&self) -> *const u8 {
         self.ptr
     }
+
+    /// for writing the pages while they are still RW, before they are made executable.
+    #[inline]
+    pub(crate) fn get_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// length of the installed code
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// mapped size, `len` rounded up to the page size
+    #[inline]
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// size of the guard area on each side, 0 if unguarded
+    #[inline]
+    pub fn guard(&self) -> usize {
+        self.guard
+    }
+
+    /// Bound the installed code to the first `len` bytes, the mapping is unchanged.
+    #[inline]
+    pub(crate) fn truncate(&mut self, len: usize) {
+        assert!(len <= self.cap, "truncate: len beyond the mapping");
+        self.len = len;
+    }
+
+    /// Typed entry at `offset` into the installed code, it can not outlive the handle.
+    ///
+    /// # Safety
+    /// the code at `offset` must have the signature `F`.
+    #[inline]
+    pub unsafe fn get_function<F: JitFn>(&self, offset: usize) -> JitFunction<'_, F> {
+        assert!(offset < self.len, "get_function offset invalid");
+        JitFunction::from_raw(self.ptr.add(offset))
+    }
+
+    /// writable view of the pages, only available on dual mapped handles.
+    #[inline]
+    pub fn get_write_ptr(&self) -> Option<*mut u8> {
+        self.alias
+    }
+
+    #[inline]
+    pub fn is_dual_mapped(&self) -> bool {
+        self.alias.is_some()
+    }
 }
 
 #[cfg(unix)]
 impl PageHandle {
+    /// `hint` is only a hint, the caller checks where the pages really are.
     #[inline]
-    pub fn new(size: usize, page_size: PageSize) -> Self {
-        use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+    fn try_new_at(
+        size: usize,
+        page_size: PageSize,
+        hint: *mut u8,
+        guard: usize,
+    ) -> Result<Self, PageError> {
+        use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
         unsafe {
-            let page_size = page_size.0;
-            let size_mod = size % page_size;
-            let alloc_size = if size_mod == 0 {
-                size
+            let (alloc_size, total) = alloc_sizes(size, page_size, guard)?;
+            let prot = if guard == 0 {
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
             } else {
-                size + page_size - size_mod
+                ProtFlags::PROT_NONE
             };
-            let ptr = mmap(
-                std::ptr::null_mut(),
-                alloc_size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            let base = mmap(
+                hint as *mut c_void,
+                total,
+                prot,
                 MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
                 -1,
                 0,
             )
-            .unwrap();
-            if ptr.is_null() {
-                panic!("VirtualAlloc failed");
+            .map_err(|e| PageError::Alloc(e as i32))?;
+            let ptr = (base as *mut u8).add(guard);
+            if guard != 0 {
+                mprotect(
+                    ptr as *mut c_void,
+                    alloc_size,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                )
+                .map_err(|e| {
+                    let _ = munmap(base, total);
+                    PageError::Protect(e as i32)
+                })?;
             }
-            PageHandle {
-                ptr: ptr as *mut u8,
+            Ok(PageHandle {
+                ptr,
+                alias: None,
                 len: size,
                 cap: alloc_size,
-            }
+                guard,
+            })
         }
     }
 
+    /// `hint` is only a hint for the RX view, the caller checks where the pages really are.
     #[inline]
-    pub fn make_page_executable(&self) {
+    fn try_new_dual_at(
+        size: usize,
+        page_size: PageSize,
+        hint: *mut u8,
+        guard: usize,
+    ) -> Result<Self, PageError> {
+        use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+        use nix::unistd::{close, ftruncate};
+        unsafe {
+            let (alloc_size, _) = alloc_sizes(size, page_size, guard)?;
+            let fd = shared_memory_fd().map_err(|e| PageError::Alloc(e as i32))?;
+            let r = ftruncate(fd, alloc_size as nix::libc::off_t)
+                .and_then(|_| {
+                    mmap(
+                        std::ptr::null_mut(),
+                        alloc_size,
+                        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                        MapFlags::MAP_SHARED,
+                        fd,
+                        0,
+                    )
+                })
+                .and_then(|alias| {
+                    map_exec_view(fd, alloc_size, hint, guard)
+                        .map(|ptr| (alias, ptr))
+                        .inspect_err(|_| {
+                            let _ = munmap(alias, alloc_size);
+                        })
+                });
+            // the mappings keep the memory alive
+            let _ = close(fd);
+            let (alias, ptr) = r.map_err(|e| PageError::Alloc(e as i32))?;
+            Ok(PageHandle {
+                ptr,
+                alias: Some(alias as *mut u8),
+                len: size,
+                cap: alloc_size,
+                guard,
+            })
+        }
+    }
+
+    #[inline]
+    fn try_make_writable(&self) -> Result<(), PageError> {
         use nix::sys::mman::{mprotect, ProtFlags};
+        unsafe {
+            mprotect(
+                self.ptr as *mut c_void,
+                self.cap,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            )
+            .map_err(|e| PageError::Protect(e as i32))
+        }
+    }
+
+    #[inline]
+    pub fn try_make_executable(&self) -> Result<(), PageError> {
+        use nix::sys::mman::{mprotect, ProtFlags};
+        if self.is_dual_mapped() {
+            // the executable view is already RX
+            return Ok(());
+        }
         unsafe {
             mprotect(
                 self.ptr as *mut c_void,
                 self.cap,
                 ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
             )
-            .unwrap();
+            .map_err(|e| PageError::Protect(e as i32))
+        }
+    }
+
+    /// Change the protection of `offset..offset + len` of a single mapped handle,
+    /// `offset` must be page aligned.
+    pub fn try_protect(
+        &self,
+        offset: usize,
+        len: usize,
+        prot: Protection,
+    ) -> Result<(), PageError> {
+        use nix::sys::mman::{mprotect, ProtFlags};
+        if !matches!(offset.checked_add(len), Some(end) if end <= self.cap) {
+            return Err(PageError::OutOfRange { offset, len });
+        }
+        let flags = match prot {
+            Protection::ReadOnly => ProtFlags::PROT_READ,
+            Protection::ReadWrite => ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            Protection::ReadExecute => ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+        };
+        unsafe {
+            mprotect(self.ptr.add(offset) as *mut c_void, len, flags)
+                .map_err(|e| PageError::Protect(e as i32))
         }
     }
 }
 
 #[cfg(windows)]
 impl PageHandle {
+    /// a non null `hint` fails when the address is already in use.
     #[inline]
-    pub fn new(size: usize, page_size: PageSize) -> Self {
+    fn try_new_at(
+        size: usize,
+        page_size: PageSize,
+        hint: *mut u8,
+        guard: usize,
+    ) -> Result<Self, PageError> {
         use winapi::um::{memoryapi, winnt};
         unsafe {
-            let page_size = page_size.0;
-            let size_mod = size % page_size;
-            let alloc_size = if size_mod == 0 {
-                size
-            } else {
-                size + page_size - size_mod
-            };
+            let (alloc_size, total) = alloc_sizes(size, page_size, guard)?;
+            let base = memoryapi::VirtualAlloc(
+                hint as *mut c_void,
+                total,
+                winnt::MEM_RESERVE,
+                winnt::PAGE_NOACCESS,
+            );
+            if base.is_null() {
+                return Err(PageError::Alloc(last_os_error()));
+            }
             let ptr = memoryapi::VirtualAlloc(
-                std::ptr::null_mut(),
+                (base as *mut u8).add(guard) as *mut c_void,
                 alloc_size,
                 winnt::MEM_COMMIT,
                 winnt::PAGE_READWRITE,
             );
             if ptr.is_null() {
-                panic!("VirtualAlloc failed");
+                let err = last_os_error();
+                memoryapi::VirtualFree(base, 0, winnt::MEM_RELEASE);
+                return Err(PageError::Alloc(err));
+            }
+            Ok(PageHandle {
+                ptr: ptr as *mut u8,
+                alias: None,
+                len: size,
+                cap: alloc_size,
+                guard,
+            })
+        }
+    }
+
+    /// a non null `hint` for the RX view fails when the address is already in use.
+    /// Guard pages are not supported here, views can not be placed inside a reservation.
+    #[inline]
+    fn try_new_dual_at(
+        size: usize,
+        page_size: PageSize,
+        hint: *mut u8,
+        guard: usize,
+    ) -> Result<Self, PageError> {
+        use winapi::um::{handleapi, memoryapi, winnt};
+        if guard != 0 {
+            return Err(PageError::Alloc(
+                winapi::shared::winerror::ERROR_NOT_SUPPORTED as i32,
+            ));
+        }
+        unsafe {
+            let (alloc_size, _) = alloc_sizes(size, page_size, guard)?;
+            let mapping = memoryapi::CreateFileMappingW(
+                handleapi::INVALID_HANDLE_VALUE,
+                std::ptr::null_mut(),
+                winnt::PAGE_EXECUTE_READWRITE,
+                ((alloc_size as u64) >> 32) as u32,
+                alloc_size as u32,
+                std::ptr::null(),
+            );
+            if mapping.is_null() {
+                return Err(PageError::Alloc(last_os_error()));
             }
-            PageHandle {
+            let alias =
+                memoryapi::MapViewOfFile(mapping, memoryapi::FILE_MAP_WRITE, 0, 0, alloc_size);
+            let ptr = memoryapi::MapViewOfFileEx(
+                mapping,
+                memoryapi::FILE_MAP_READ | memoryapi::FILE_MAP_EXECUTE,
+                0,
+                0,
+                alloc_size,
+                hint as *mut c_void,
+            );
+            let err = last_os_error();
+            // the views keep the section alive
+            handleapi::CloseHandle(mapping);
+            if alias.is_null() || ptr.is_null() {
+                if !alias.is_null() {
+                    memoryapi::UnmapViewOfFile(alias);
+                }
+                if !ptr.is_null() {
+                    memoryapi::UnmapViewOfFile(ptr);
+                }
+                return Err(PageError::Alloc(err));
+            }
+            Ok(PageHandle {
                 ptr: ptr as *mut u8,
+                alias: Some(alias as *mut u8),
                 len: size,
                 cap: alloc_size,
+                guard: 0,
+            })
+        }
+    }
+
+    #[inline]
+    fn try_make_writable(&self) -> Result<(), PageError> {
+        use winapi::um::{memoryapi, winnt};
+        unsafe {
+            let mut flag = winnt::PAGE_EXECUTE_READ;
+            let r = memoryapi::VirtualProtect(
+                self.ptr as *mut c_void,
+                self.cap,
+                winnt::PAGE_READWRITE,
+                &mut flag,
+            );
+            if r == 0 {
+                return Err(PageError::Protect(last_os_error()));
             }
         }
+        Ok(())
     }
 
     #[inline]
-    pub fn make_page_executable(&self) {
+    pub fn try_make_executable(&self) -> Result<(), PageError> {
         use winapi::um::{memoryapi, winnt};
+        if self.is_dual_mapped() {
+            // the executable view is already RX
+            return Ok(());
+        }
         unsafe {
             let mut flag = winnt::PAGE_READWRITE;
             let r = memoryapi::VirtualProtect(
@@ -169,19 +788,192 @@ impl PageHandle {
                 &mut flag,
             );
             if r == 0 {
-                panic!("VirtualProtect failed");
+                return Err(PageError::Protect(last_os_error()));
             }
         }
+        Ok(())
+    }
+
+    /// Change the protection of `offset..offset + len` of a single mapped handle,
+    /// `offset` must be page aligned.
+    pub fn try_protect(
+        &self,
+        offset: usize,
+        len: usize,
+        prot: Protection,
+    ) -> Result<(), PageError> {
+        use winapi::um::{memoryapi, winnt};
+        if !matches!(offset.checked_add(len), Some(end) if end <= self.cap) {
+            return Err(PageError::OutOfRange { offset, len });
+        }
+        let new_flag = match prot {
+            Protection::ReadOnly => winnt::PAGE_READONLY,
+            Protection::ReadWrite => winnt::PAGE_READWRITE,
+            Protection::ReadExecute => winnt::PAGE_EXECUTE_READ,
+        };
+        unsafe {
+            let mut flag = 0;
+            let r = memoryapi::VirtualProtect(
+                self.ptr.add(offset) as *mut c_void,
+                len,
+                new_flag,
+                &mut flag,
+            );
+            if r == 0 {
+                return Err(PageError::Protect(last_os_error()));
+            }
+        }
+        Ok(())
     }
 }
 
+/// whether a rel32 displacement can encode `to - from`.
+#[inline]
+pub fn rel32_reachable(from: usize, to: usize) -> bool {
+    let d = to as i128 - from as i128;
+    d >= i32::MIN as i128 && d <= i32::MAX as i128
+}
+
+/// distance between two tried placements, also a multiple of the windows allocation granularity.
+const NEAR_STEP: usize = 64 << 20;
+
+/// Placements alternating above and below `target`, closest first.
+fn near_candidates(target: usize) -> impl Iterator<Item = usize> {
+    let base = target & !(NEAR_STEP - 1);
+    let steps = i32::MAX as usize / NEAR_STEP;
+    (1..steps)
+        .flat_map(move |i| {
+            let above = base.checked_add(i * NEAR_STEP);
+            let below = base.checked_sub(i * NEAR_STEP);
+            above.into_iter().chain(below)
+        })
+        .filter(|&hint| hint != 0)
+}
+
+/// x86 keeps the instruction cache coherent with data writes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+pub fn flush_icache(_ptr: *const u8, _len: usize) {}
+
+#[cfg(all(unix, not(any(target_arch = "x86", target_arch = "x86_64"))))]
+#[inline]
+pub fn flush_icache(ptr: *const u8, len: usize) {
+    extern "C" {
+        // provided by libgcc / compiler-rt
+        fn __clear_cache(start: *mut std::os::raw::c_char, end: *mut std::os::raw::c_char);
+    }
+    unsafe {
+        __clear_cache(ptr as *mut _, ptr.add(len) as *mut _);
+    }
+}
+
+#[cfg(all(windows, not(any(target_arch = "x86", target_arch = "x86_64"))))]
+#[inline]
+pub fn flush_icache(ptr: *const u8, len: usize) {
+    use winapi::um::processthreadsapi::{FlushInstructionCache, GetCurrentProcess};
+    unsafe {
+        FlushInstructionCache(GetCurrentProcess(), ptr as *const c_void, len);
+    }
+}
+
+/// Map the RX view of `fd`, inside a PROT_NONE reservation when guarded.
+#[cfg(unix)]
+unsafe fn map_exec_view(
+    fd: std::os::unix::io::RawFd,
+    alloc_size: usize,
+    hint: *mut u8,
+    guard: usize,
+) -> nix::Result<*mut u8> {
+    use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+    if guard == 0 {
+        return mmap(
+            hint as *mut c_void,
+            alloc_size,
+            ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+            MapFlags::MAP_SHARED,
+            fd,
+            0,
+        )
+        .map(|ptr| ptr as *mut u8);
+    }
+    // checked by `alloc_sizes` already
+    let total = alloc_size + 2 * guard;
+    let base = mmap(
+        hint as *mut c_void,
+        total,
+        ProtFlags::PROT_NONE,
+        MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+        -1,
+        0,
+    )?;
+    mmap(
+        (base as *mut u8).add(guard) as *mut c_void,
+        alloc_size,
+        ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+        MapFlags::MAP_SHARED | MapFlags::MAP_FIXED,
+        fd,
+        0,
+    )
+    .map(|ptr| ptr as *mut u8)
+    .inspect_err(|_| {
+        let _ = munmap(base, total);
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn shared_memory_fd() -> nix::Result<std::os::unix::io::RawFd> {
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    let name = std::ffi::CString::new("emei-jit").unwrap();
+    memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn shared_memory_fd() -> nix::Result<std::os::unix::io::RawFd> {
+    use nix::fcntl::OFlag;
+    use nix::sys::mman::{shm_open, shm_unlink};
+    use nix::sys::stat::Mode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "/emei-jit-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let fd = shm_open(
+        name.as_str(),
+        OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+    // only the fd is needed, drop the name right away
+    shm_unlink(name.as_str())?;
+    Ok(fd)
+}
+
 #[cfg(unix)]
 impl Drop for PageHandle {
     #[inline]
     fn drop(&mut self) {
         use nix::sys::mman::munmap;
+        if self.guard != 0 {
+            self.unregister_guard();
+        }
+        #[cfg(target_pointer_width = "64")]
+        crate::gdb_jit::unregister_range(self.ptr, self.cap);
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        crate::trap::unregister_range(self.ptr, self.cap);
+        // nothing can be done about a failed unmap here, leak the pages instead of panicking
         unsafe {
-            munmap(self.ptr as *mut c_void, self.cap).unwrap();
+            let _ = munmap(
+                self.ptr.sub(self.guard) as *mut c_void,
+                self.cap + 2 * self.guard,
+            );
+            if let Some(alias) = self.alias {
+                let _ = munmap(alias as *mut c_void, self.cap);
+            }
         }
     }
 }
@@ -191,8 +983,22 @@ impl Drop for PageHandle {
     #[inline]
     fn drop(&mut self) {
         use winapi::um::{memoryapi, winnt};
+        if self.guard != 0 {
+            self.unregister_guard();
+        }
+        #[cfg(target_pointer_width = "64")]
+        crate::gdb_jit::unregister_range(self.ptr, self.cap);
         unsafe {
-            memoryapi::VirtualFree(self.ptr as *mut c_void, 0, winnt::MEM_RELEASE);
+            if let Some(alias) = self.alias {
+                memoryapi::UnmapViewOfFile(self.ptr as *mut c_void);
+                memoryapi::UnmapViewOfFile(alias as *mut c_void);
+            } else {
+                memoryapi::VirtualFree(
+                    self.ptr.sub(self.guard) as *mut c_void,
+                    0,
+                    winnt::MEM_RELEASE,
+                );
+            }
         }
     }
 }
@@ -0,0 +1,193 @@
+//! Rewriting patch sites of installed code (x86_64), for inline caches and retargetable calls.
+//!
+//! `InstBuffer::patchable` reserves a site as a single nop inside one aligned 8 byte word.
+//! A rewrite replaces that word with one atomic store through the writable view,
+//! so a thread executing the code concurrently fetches either the old or the new instruction,
+//! never a mix (the cross-modifying code rule for x86: the modified bytes are written atomically
+//! and do not cross the boundary of an aligned store).
+//!
+//! A thread may be stopped at an instruction boundary inside the site, e.g. returning from the call in it.
+//! Rewrites must keep those boundaries: a site of 5 bytes or more always has one after its first 5 bytes
+//! (the length of `rewrite_call`/`rewrite_jmp`), `rewrite` pads with nops up to it and up to the end.
+
+use std::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    code_cache::{CodeBlock, CodeCache, SharedCode},
+    insts::x86_64::inst_dump_buf::{nop_fill, patch_site_head, PatchSite, MAX_PATCH_SITE_LEN},
+    page_manage::{flush_icache, rel32_reachable, PageError, PageHandle},
+};
+
+/// A patch site of installed code, it borrows the code's owner for `'a`.
+#[derive(Debug, Clone, Copy)]
+pub struct PatchHandle<'a> {
+    ptr: *const u8,
+    write_ptr: *mut u8,
+    len: usize,
+    _marker: PhantomData<&'a ()>,
+}
+
+// every rewrite is a single atomic store
+unsafe impl<'a> Send for PatchHandle<'a> {}
+unsafe impl<'a> Sync for PatchHandle<'a> {}
+
+impl<'a> PatchHandle<'a> {
+    /// `None` when the site crosses an 8 byte boundary at its installed address.
+    ///
+    /// # Safety
+    /// `ptr..ptr + len` must be installed code whose writable view is `write_ptr`,
+    /// both live for `'a`.
+    pub unsafe fn from_raw(ptr: *const u8, write_ptr: *mut u8, len: usize) -> Option<Self> {
+        if len == 0 || ptr as usize % MAX_PATCH_SITE_LEN + len > MAX_PATCH_SITE_LEN {
+            return None;
+        }
+        // the views map the same pages, the word boundaries must agree
+        debug_assert_eq!(
+            ptr as usize % MAX_PATCH_SITE_LEN,
+            write_ptr as usize % MAX_PATCH_SITE_LEN
+        );
+        Some(PatchHandle {
+            ptr,
+            write_ptr,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    /// `Err(OutOfRange)` when the site is not inside the code.
+    pub fn from_shared_code(
+        code: &'a SharedCode,
+        site: PatchSite,
+    ) -> Result<Option<Self>, PageError> {
+        let (offset, len) = site_range(site, code.len())?;
+        unsafe {
+            Ok(Self::from_raw(
+                code.get_ptr().add(offset),
+                code.get_write_ptr().add(offset),
+                len,
+            ))
+        }
+    }
+
+    /// `Err(OutOfRange)` when the site is not inside the block.
+    pub fn from_code_cache(
+        cache: &'a CodeCache,
+        block: &CodeBlock,
+        site: PatchSite,
+    ) -> Result<Option<Self>, PageError> {
+        let (offset, len) = site_range(site, block.len())?;
+        unsafe {
+            Ok(Self::from_raw(
+                cache.get_ptr(block).add(offset),
+                cache.get_write_ptr(block).add(offset),
+                len,
+            ))
+        }
+    }
+
+    /// `Err(OutOfRange)` when the site is not inside the page's code,
+    /// `None` for single mapped handles, their pages are never writable while executable.
+    pub fn from_page(page: &'a PageHandle, site: PatchSite) -> Result<Option<Self>, PageError> {
+        let (offset, len) = site_range(site, page.len())?;
+        let write_ptr = match page.get_write_ptr() {
+            Some(write_ptr) => write_ptr,
+            None => return Ok(None),
+        };
+        unsafe {
+            Ok(Self::from_raw(
+                page.get_ptr().add(offset),
+                write_ptr.add(offset),
+                len,
+            ))
+        }
+    }
+
+    #[inline]
+    pub fn get_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    #[inline]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// the current bytes of the site.
+    pub fn read(&self) -> Vec<u8> {
+        let (word, shift) = self.word();
+        let bytes = word.load(Ordering::Acquire).to_le_bytes();
+        bytes[shift..shift + self.len].to_vec()
+    }
+
+    /// Replace the site with `code`, padded with nops to the site's instruction boundaries.
+    /// `code` must fit before the first boundary (`patch_site_head`).
+    pub fn rewrite(&self, code: &[u8]) {
+        let head = patch_site_head(self.len);
+        assert!(
+            code.len() <= head,
+            "PatchHandle: code crosses an instruction boundary of the site"
+        );
+        let mut new = code.to_vec();
+        new.extend(nop_fill(head - code.len()));
+        new.extend(nop_fill(self.len - head));
+
+        let (word, shift) = self.word();
+        // neighbouring bytes may be another site rewritten concurrently
+        let _ = word.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+            let mut bytes = old.to_le_bytes();
+            bytes[shift..shift + self.len].copy_from_slice(&new);
+            Some(u64::from_le_bytes(bytes))
+        });
+        flush_icache(self.ptr, self.len);
+    }
+
+    /// `call target`, the site must be at least 5 bytes.
+    pub fn rewrite_call(&self, target: *const u8) -> Result<(), PageError> {
+        self.rewrite_rel32(0xe8, target)
+    }
+
+    /// `jmp target`, the site must be at least 5 bytes.
+    pub fn rewrite_jmp(&self, target: *const u8) -> Result<(), PageError> {
+        self.rewrite_rel32(0xe9, target)
+    }
+
+    /// back to the nops of a fresh site.
+    pub fn reset(&self) {
+        self.rewrite(&[])
+    }
+
+    fn rewrite_rel32(&self, opcode: u8, target: *const u8) -> Result<(), PageError> {
+        assert!(self.len >= 5, "PatchHandle: site shorter than 5 bytes");
+        let next = self.ptr as usize + 5;
+        if !rel32_reachable(next, target as usize) {
+            return Err(PageError::OutOfReach);
+        }
+        let mut code = vec![opcode];
+        code.extend(((target as usize).wrapping_sub(next) as u32).to_le_bytes());
+        self.rewrite(&code);
+        Ok(())
+    }
+
+    /// the aligned word holding the site and the site's position in it.
+    #[inline]
+    fn word(&self) -> (&AtomicU64, usize) {
+        let addr = self.write_ptr as usize;
+        let shift = addr % MAX_PATCH_SITE_LEN;
+        let word = unsafe { &*((addr - shift) as *const AtomicU64) };
+        (word, shift)
+    }
+}
+
+/// the offset and length of `site` if it ends within `code_len` bytes.
+fn site_range(site: PatchSite, code_len: usize) -> Result<(usize, usize), PageError> {
+    let offset = site.offset as usize;
+    let len = site.len as usize;
+    match offset.checked_add(len) {
+        Some(end) if end <= code_len => Ok((offset, len)),
+        _ => Err(PageError::OutOfRange { offset, len }),
+    }
+}
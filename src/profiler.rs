@@ -0,0 +1,211 @@
+//! Opt-in `perf` integration for installed code.
+//!
+//! - perf map: `/tmp/perf-<pid>.map`, one `START SIZE name` line per symbol.
+//! - jitdump: `/tmp/jit-<pid>.dump`, also carries the code bytes,
+//!   use `perf record -k mono` and `perf inject --jit` (linux only).
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+
+#[derive(Debug, Default)]
+struct Profiler {
+    perf_map: Option<File>,
+    #[cfg(target_os = "linux")]
+    jitdump: Option<jitdump::JitDump>,
+}
+
+lazy_static! {
+    static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler::default());
+}
+
+/// fast path for `record_code` when nothing is enabled
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn perf_map_path() -> String {
+    format!("/tmp/perf-{}.map", std::process::id())
+}
+
+pub fn enable_perf_map() -> io::Result<()> {
+    let mut profiler = PROFILER.lock().unwrap();
+    if profiler.perf_map.is_none() {
+        let f = File::options()
+            .append(true)
+            .create(true)
+            .open(perf_map_path())?;
+        profiler.perf_map = Some(f);
+    }
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn jitdump_path() -> String {
+    format!("/tmp/jit-{}.dump", std::process::id())
+}
+
+#[cfg(target_os = "linux")]
+pub fn enable_jitdump() -> io::Result<()> {
+    let mut profiler = PROFILER.lock().unwrap();
+    if profiler.jitdump.is_none() {
+        profiler.jitdump = Some(jitdump::JitDump::create(&jitdump_path())?);
+    }
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// stop recording, files already written are kept for `perf report`.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+    *PROFILER.lock().unwrap() = Profiler::default();
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Record `len` bytes of installed code at `ptr` under `name`, no-op when nothing is enabled.
+///
+/// # Safety
+/// `ptr..ptr + len` must be readable.
+pub unsafe fn record_code(name: &str, ptr: *const u8, len: usize) -> io::Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let mut profiler = PROFILER.lock().unwrap();
+    if let Some(f) = profiler.perf_map.as_mut() {
+        writeln!(f, "{:x} {:x} {}", ptr as usize, len, name)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(dump) = profiler.jitdump.as_mut() {
+        let code = std::slice::from_raw_parts(ptr, len);
+        dump.code_load(name, ptr as u64, code)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod jitdump {
+    use std::{
+        fs::File,
+        io::{self, Write},
+        os::unix::io::AsRawFd,
+    };
+
+    const JITDUMP_MAGIC: u32 = 0x4A695444;
+    const JITDUMP_VERSION: u32 = 1;
+    const JIT_CODE_LOAD: u32 = 0;
+    const FILE_HEADER_SIZE: u32 = 40;
+    const RECORD_HEADER_SIZE: u32 = 16;
+    const CODE_LOAD_SIZE: u32 = RECORD_HEADER_SIZE + 40;
+
+    #[cfg(target_arch = "x86_64")]
+    const ELF_MACH: u32 = 62;
+    #[cfg(target_arch = "x86")]
+    const ELF_MACH: u32 = 3;
+    #[cfg(target_arch = "aarch64")]
+    const ELF_MACH: u32 = 183;
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    const ELF_MACH: u32 = 243;
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )))]
+    const ELF_MACH: u32 = 0;
+
+    #[derive(Debug)]
+    pub(super) struct JitDump {
+        file: File,
+        code_index: u64,
+        /// the executable mapping of the file perf looks for, (addr, len)
+        marker: (usize, usize),
+    }
+
+    fn timestamp() -> u64 {
+        use nix::time::{clock_gettime, ClockId};
+        clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .map(|t| t.tv_sec() as u64 * 1_000_000_000 + t.tv_nsec() as u64)
+            .unwrap_or(0)
+    }
+
+    impl JitDump {
+        pub(super) fn create(path: &str) -> io::Result<Self> {
+            use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+            header.extend(JITDUMP_MAGIC.to_ne_bytes());
+            header.extend(JITDUMP_VERSION.to_ne_bytes());
+            header.extend(FILE_HEADER_SIZE.to_ne_bytes());
+            header.extend(ELF_MACH.to_ne_bytes());
+            header.extend(0u32.to_ne_bytes());
+            header.extend(std::process::id().to_ne_bytes());
+            header.extend(timestamp().to_ne_bytes());
+            header.extend(0u64.to_ne_bytes());
+            file.write_all(&header)?;
+
+            // perf finds the dump file through this executable mmap event
+            let len = crate::page_manage::PageSize::from_system().0;
+            let marker = unsafe {
+                mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+                    MapFlags::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            }
+            .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+            Ok(JitDump {
+                file,
+                code_index: 0,
+                marker: (marker as usize, len),
+            })
+        }
+
+        pub(super) fn code_load(&mut self, name: &str, addr: u64, code: &[u8]) -> io::Result<()> {
+            let total_size = CODE_LOAD_SIZE as usize + name.len() + 1 + code.len();
+            let mut record = Vec::with_capacity(total_size);
+            record.extend(JIT_CODE_LOAD.to_ne_bytes());
+            record.extend((total_size as u32).to_ne_bytes());
+            record.extend(timestamp().to_ne_bytes());
+            record.extend(std::process::id().to_ne_bytes());
+            record.extend((nix::unistd::gettid().as_raw() as u32).to_ne_bytes());
+            record.extend(addr.to_ne_bytes());
+            record.extend(addr.to_ne_bytes());
+            record.extend((code.len() as u64).to_ne_bytes());
+            record.extend(self.code_index.to_ne_bytes());
+            record.extend(name.as_bytes());
+            record.push(0);
+            record.extend(code);
+            self.code_index += 1;
+            self.file.write_all(&record)
+        }
+    }
+
+    impl Drop for JitDump {
+        fn drop(&mut self) {
+            use nix::sys::mman::munmap;
+            unsafe {
+                let _ = munmap(self.marker.0 as *mut std::ffi::c_void, self.marker.1);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Quiescent-state based reclamation of code memory.
+//!
+//! Code that may still be running on another thread can not be unmapped right away.
+//! Retire it with `Reclaimer::retire` instead, it is dropped by `Reclaimer::try_reclaim`
+//! once every online `Participant` passed a quiescent state after the retirement.
+//!
+//! A thread executing jitted code registers a `Participant` and calls `quiescent`
+//! at points where it holds no code pointer and runs no jitted code
+//! (between interpreter dispatches, at safepoints...).
+//! A thread that stops running jitted code for a while goes `offline` so it does not hold reclamation back,
+//! it must not load code pointers until it is `online` again.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// epoch of an offline participant
+const OFFLINE: u64 = u64::MAX;
+
+#[derive(Debug)]
+struct ThreadState {
+    /// the global epoch seen at the last quiescent state
+    epoch: AtomicU64,
+}
+
+struct Retired {
+    epoch: u64,
+    /// only kept to be dropped
+    #[allow(dead_code)]
+    item: Box<dyn Send>,
+}
+
+impl std::fmt::Debug for Retired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Retired")
+            .field("epoch", &self.epoch)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    epoch: AtomicU64,
+    threads: Mutex<Vec<Arc<ThreadState>>>,
+    retired: Mutex<Vec<Retired>>,
+}
+
+/// Shared between all threads that run or retire jitted code, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Reclaimer(Arc<Inner>);
+
+impl Default for Reclaimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reclaimer {
+    pub fn new() -> Self {
+        Reclaimer(Arc::new(Inner {
+            epoch: AtomicU64::new(1),
+            threads: Mutex::new(vec![]),
+            retired: Mutex::new(vec![]),
+        }))
+    }
+
+    /// Register the calling thread, it starts online and quiescent.
+    pub fn register(&self) -> Participant {
+        let state = Arc::new(ThreadState {
+            epoch: AtomicU64::new(self.0.epoch.load(Ordering::SeqCst)),
+        });
+        self.0.threads.lock().unwrap().push(state.clone());
+        Participant {
+            reclaimer: self.clone(),
+            state,
+        }
+    }
+
+    /// Hand over code memory (`PageHandle`, `SharedCode`...) that is no longer published.
+    /// It is dropped by a later `try_reclaim` once no thread can still be executing it.
+    ///
+    /// Unpublish first (swap out the function pointer), retire after.
+    pub fn retire<T: Send + 'static>(&self, item: T) {
+        let epoch = self.0.epoch.fetch_add(1, Ordering::SeqCst);
+        self.0.retired.lock().unwrap().push(Retired {
+            epoch,
+            item: Box::new(item),
+        });
+    }
+
+    /// Drop everything every online participant has moved past, returns how many items were dropped.
+    pub fn try_reclaim(&self) -> usize {
+        let safe = self
+            .0
+            .threads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| t.epoch.load(Ordering::SeqCst))
+            .min()
+            .unwrap_or(OFFLINE);
+        let reclaimed: Vec<Retired> = {
+            let mut retired = self.0.retired.lock().unwrap();
+            let (reclaimed, pending) = retired.drain(..).partition(|r| r.epoch < safe);
+            *retired = pending;
+            reclaimed
+        };
+        // dropped outside of the lock, dropping code may retire more
+        reclaimed.len()
+    }
+
+    /// number of retired items not dropped yet
+    pub fn pending(&self) -> usize {
+        self.0.retired.lock().unwrap().len()
+    }
+}
+
+/// A registered thread, see the module documentation.
+/// Dropping it unregisters the thread.
+#[derive(Debug)]
+pub struct Participant {
+    reclaimer: Reclaimer,
+    state: Arc<ThreadState>,
+}
+
+impl Participant {
+    /// The thread holds no code pointer and runs no jitted code right now.
+    #[inline]
+    pub fn quiescent(&self) {
+        let epoch = self.reclaimer.0.epoch.load(Ordering::SeqCst);
+        self.state.epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// Stop holding reclamation back, implies a quiescent state.
+    #[inline]
+    pub fn offline(&self) {
+        self.state.epoch.store(OFFLINE, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn online(&self) {
+        self.quiescent();
+    }
+
+    #[inline]
+    pub fn is_online(&self) -> bool {
+        self.state.epoch.load(Ordering::SeqCst) != OFFLINE
+    }
+}
+
+impl Drop for Participant {
+    fn drop(&mut self) {
+        if let Ok(mut threads) = self.reclaimer.0.threads.lock() {
+            threads.retain(|t| !Arc::ptr_eq(t, &self.state));
+        }
+    }
+}
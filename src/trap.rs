@@ -0,0 +1,392 @@
+//! Opt-in trap handling for generated code (linux, x86_64/aarch64).
+//!
+//! `install` puts SIGSEGV/SIGBUS/SIGILL/SIGFPE/SIGTRAP handlers in place.
+//! A fault whose pc is inside code registered with `register_code` either
+//! - resumes at the recovery address given at registration, `take_last_trap` tells what happened, or
+//! - returns `Err(Trap)` from the enclosing `call_catching` (x86_64 only).
+//!
+//! Every other fault goes to the previously installed handler.
+//! Faults are handled on the faulting stack, overflowing the stack is not caught.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use lazy_static::lazy_static;
+use nix::{
+    libc,
+    sys::signal::{raise, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+
+const TRAP_SIGNALS: [Signal; 5] = [
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGILL,
+    Signal::SIGFPE,
+    Signal::SIGTRAP,
+];
+
+/// Where a trap happened inside registered code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrapLocation {
+    /// start of the registered code
+    pub start: usize,
+    /// pc - start
+    pub offset: usize,
+    /// the nearest label at or before `offset` and the distance from it
+    pub label: Option<(String, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub signal: Signal,
+    pub pc: usize,
+    /// si_addr, the faulting memory address for SIGSEGV/SIGBUS
+    pub fault_addr: usize,
+    pub location: Option<TrapLocation>,
+}
+
+#[derive(Debug)]
+pub struct TrapError(pub nix::Error);
+
+/// `register_code` found all `MAX_REGIONS` slots taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistryFull;
+
+///////////////////////////////////////////////////////
+// registered code
+
+/// signal handler side of the registry, read without locks
+struct Slot {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    recovery: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    recovery: AtomicUsize::new(0),
+};
+
+/// how many regions can be registered at once
+pub const MAX_REGIONS: usize = 1024;
+
+static SLOTS: [Slot; MAX_REGIONS] = [EMPTY_SLOT; MAX_REGIONS];
+
+/// fast path for `unregister_code` from `Drop`
+static ANY_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+struct Labels {
+    start: usize,
+    /// sorted by offset
+    labels: Vec<(u32, String)>,
+}
+
+lazy_static! {
+    static ref LABELS: Mutex<Vec<Labels>> = Mutex::new(vec![]);
+}
+
+/// Register `code..code + len` for trap handling.
+/// `labels` is usually `InstBuffer::label_buf`, `recovery` is where execution resumes after a trap.
+/// Fails when `MAX_REGIONS` regions are registered.
+///
+/// Unregistered by `unregister_code`, and when the owning `PageHandle` is dropped
+/// or the `CodeCache` block is freed.
+pub fn register_code(
+    code: *const u8,
+    len: usize,
+    labels: &HashMap<String, u32>,
+    recovery: Option<*const u8>,
+) -> Result<(), RegistryFull> {
+    let mut registry = LABELS.lock().unwrap();
+    let slot = SLOTS
+        .iter()
+        .find(|s| s.start.load(Ordering::Relaxed) == 0)
+        .ok_or(RegistryFull)?;
+    slot.end.store(code as usize + len, Ordering::Relaxed);
+    slot.recovery
+        .store(recovery.map_or(0, |p| p as usize), Ordering::Relaxed);
+    slot.start.store(code as usize, Ordering::Release);
+
+    let mut labels = labels
+        .iter()
+        .map(|(k, v)| (*v, k.clone()))
+        .collect::<Vec<_>>();
+    labels.sort();
+    registry.push(Labels {
+        start: code as usize,
+        labels,
+    });
+    ANY_REGISTERED.store(true, Ordering::Release);
+    Ok(())
+}
+
+pub fn unregister_code(code: *const u8) {
+    if !ANY_REGISTERED.load(Ordering::Acquire) {
+        return;
+    }
+    let mut registry = LABELS.lock().unwrap();
+    for slot in SLOTS.iter() {
+        if slot.start.load(Ordering::Relaxed) == code as usize {
+            slot.start.store(0, Ordering::Release);
+        }
+    }
+    registry.retain(|l| l.start != code as usize);
+}
+
+/// Unregister every region starting inside `start..start + len`, for memory about to be reused.
+pub fn unregister_range(start: *const u8, len: usize) {
+    if !ANY_REGISTERED.load(Ordering::Acquire) {
+        return;
+    }
+    let range = start as usize..start as usize + len;
+    let mut registry = LABELS.lock().unwrap();
+    for slot in SLOTS.iter() {
+        if range.contains(&slot.start.load(Ordering::Relaxed)) {
+            slot.start.store(0, Ordering::Release);
+        }
+    }
+    registry.retain(|l| !range.contains(&l.start));
+}
+
+fn find_slot(pc: usize) -> Option<&'static Slot> {
+    SLOTS.iter().find(|s| {
+        let start = s.start.load(Ordering::Acquire);
+        start != 0 && start <= pc && pc < s.end.load(Ordering::Relaxed)
+    })
+}
+
+pub(crate) fn locate(pc: usize) -> Option<TrapLocation> {
+    let start = find_slot(pc)?.start.load(Ordering::Acquire);
+    let offset = pc - start;
+    let label = LABELS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|l| l.start == start)
+        .and_then(|l| {
+            l.labels
+                .iter()
+                .rev()
+                .find(|(o, _)| *o as usize <= offset)
+                .map(|(o, name)| (name.clone(), offset - *o as usize))
+        });
+    Some(TrapLocation {
+        start,
+        offset,
+        label,
+    })
+}
+
+///////////////////////////////////////////////////////
+// handler
+
+#[derive(Debug, Clone, Copy)]
+struct RawTrap {
+    signal: i32,
+    pc: usize,
+    fault_addr: usize,
+}
+
+impl RawTrap {
+    fn into_trap(self) -> Trap {
+        Trap {
+            signal: Signal::try_from(self.signal).unwrap(),
+            pc: self.pc,
+            fault_addr: self.fault_addr,
+            location: locate(self.pc),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_TRAP: Cell<Option<RawTrap>> = const { Cell::new(None) };
+    /// stack pointer saved by the `call_catching` trampoline, 0 outside of it
+    static CATCH_SP: Cell<usize> = const { Cell::new(0) };
+}
+
+static PREVIOUS: OnceLock<Vec<(Signal, SigAction)>> = OnceLock::new();
+
+/// Install the trap handlers, further calls do nothing.
+pub fn install() -> Result<(), TrapError> {
+    let mut err = None;
+    PREVIOUS.get_or_init(|| {
+        let action = SigAction::new(
+            SigHandler::SigAction(handler),
+            SaFlags::SA_NODEFER,
+            SigSet::empty(),
+        );
+        let mut previous = vec![];
+        for signal in TRAP_SIGNALS {
+            match unsafe { sigaction(signal, &action) } {
+                Ok(prev) => previous.push((signal, prev)),
+                Err(e) => err = Some(e),
+            }
+        }
+        previous
+    });
+    err.map_or(Ok(()), |e| Err(TrapError(e)))
+}
+
+/// the trap recorded by the last recovery on this thread.
+pub fn take_last_trap() -> Option<Trap> {
+    LAST_TRAP.with(|t| t.take()).map(RawTrap::into_trap)
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn context_pc(ctx: *mut libc::ucontext_t) -> usize {
+    (*ctx).uc_mcontext.gregs[libc::REG_RIP as usize] as usize
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn set_context_pc(ctx: *mut libc::ucontext_t, pc: usize) {
+    (*ctx).uc_mcontext.gregs[libc::REG_RIP as usize] = pc as i64;
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn set_context_sp(ctx: *mut libc::ucontext_t, sp: usize) {
+    (*ctx).uc_mcontext.gregs[libc::REG_RSP as usize] = sp as i64;
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn context_pc(ctx: *mut libc::ucontext_t) -> usize {
+    (*ctx).uc_mcontext.pc as usize
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn set_context_pc(ctx: *mut libc::ucontext_t, pc: usize) {
+    (*ctx).uc_mcontext.pc = pc as u64;
+}
+
+extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let ctx = ctx as *mut libc::ucontext_t;
+        let pc = context_pc(ctx);
+        if let Some(slot) = find_slot(pc) {
+            let trap = RawTrap {
+                signal,
+                pc,
+                fault_addr: (*info).si_addr() as usize,
+            };
+            let recovery = slot.recovery.load(Ordering::Relaxed);
+            if recovery != 0 {
+                LAST_TRAP.with(|t| t.set(Some(trap)));
+                set_context_pc(ctx, recovery);
+                return;
+            }
+            #[cfg(target_arch = "x86_64")]
+            {
+                let sp = CATCH_SP.with(|s| s.get());
+                if sp != 0 {
+                    LAST_TRAP.with(|t| t.set(Some(trap)));
+                    set_context_sp(ctx, sp);
+                    set_context_pc(ctx, *TRAMPOLINE + TRAMPOLINE_LANDING);
+                    return;
+                }
+            }
+        }
+        chain(signal, info, ctx as *mut c_void);
+    }
+}
+
+/// hand the signal to whoever was installed before us.
+unsafe fn chain(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let signal = Signal::try_from(signal).unwrap();
+    let prev = PREVIOUS
+        .get()
+        .and_then(|p| p.iter().find(|(s, _)| *s == signal))
+        .map(|(_, a)| a.handler());
+    match prev {
+        Some(SigHandler::Handler(f)) => f(signal as libc::c_int),
+        Some(SigHandler::SigAction(f)) => f(signal as libc::c_int, info, ctx),
+        Some(SigHandler::SigIgn) => {}
+        Some(SigHandler::SigDfl) | None => {
+            let dfl = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+            let _ = sigaction(signal, &dfl);
+            let _ = raise(signal);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////
+// Result mode
+
+/// fn(sp_slot: *mut usize, code, a0, a1, a2, a3) -> u64
+///
+/// Saves the callee-saved registers, stores the stack pointer in `sp_slot` and calls `code(a0, a1, a2, a3)`.
+/// The handler resumes a trapped call at the landing with that stack pointer,
+/// which returns to the caller as if `code` returned.
+#[cfg(target_arch = "x86_64")]
+const TRAMPOLINE_CODE: [u8; 49] = [
+    0x53, // push rbx
+    0x55, // push rbp
+    0x41, 0x54, // push r12
+    0x41, 0x55, // push r13
+    0x41, 0x56, // push r14
+    0x41, 0x57, // push r15
+    0x48, 0x83, 0xec, 0x08, // sub rsp, 8
+    0x48, 0x89, 0x27, // mov [rdi], rsp
+    0x48, 0x89, 0xf0, // mov rax, rsi
+    0x48, 0x89, 0xd7, // mov rdi, rdx
+    0x48, 0x89, 0xce, // mov rsi, rcx
+    0x4c, 0x89, 0xc2, // mov rdx, r8
+    0x4c, 0x89, 0xc9, // mov rcx, r9
+    0xff, 0xd0, // call rax
+    // landing:
+    0x48, 0x83, 0xc4, 0x08, // add rsp, 8
+    0x41, 0x5f, // pop r15
+    0x41, 0x5e, // pop r14
+    0x41, 0x5d, // pop r13
+    0x41, 0x5c, // pop r12
+    0x5d, // pop rbp
+    0x5b, // pop rbx
+    0xc3, // ret
+];
+
+#[cfg(target_arch = "x86_64")]
+const TRAMPOLINE_LANDING: usize = 34;
+
+#[cfg(target_arch = "x86_64")]
+lazy_static! {
+    /// the trampoline lives as long as the process
+    static ref TRAMPOLINE: usize = {
+        use crate::page_manage::{PageHandle, PageSize};
+        let r = PageHandle::from(PageSize::from_system(), &TRAMPOLINE_CODE);
+        let ptr = r.get_ptr() as usize;
+        std::mem::forget(r);
+        ptr
+    };
+}
+
+/// Call registered code at `code` with up to 4 integer arguments,
+/// a trap inside registered code without a recovery address becomes `Err(Trap)`.
+///
+/// # Safety
+/// `code` must be an `extern "C"` function taking `args.len()` integer arguments and returning an integer.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn call_catching(code: *const u8, args: &[u64]) -> Result<u64, Trap> {
+    assert!(args.len() <= 4, "call_catching: at most 4 arguments");
+    let mut a = [0u64; 4];
+    a[..args.len()].copy_from_slice(args);
+    type Trampoline = extern "C" fn(*mut usize, *const u8, u64, u64, u64, u64) -> u64;
+    let trampoline: Trampoline = std::mem::transmute(*TRAMPOLINE);
+
+    let sp_slot = CATCH_SP.with(|s| s.as_ptr());
+    let outer_sp = *sp_slot;
+    let outer_trap = LAST_TRAP.with(|t| t.take());
+    let r = trampoline(sp_slot, code, a[0], a[1], a[2], a[3]);
+    *sp_slot = outer_sp;
+    let trap = LAST_TRAP.with(|t| t.replace(outer_trap));
+    match trap {
+        Some(trap) => Err(trap.into_trap()),
+        None => Ok(r),
+    }
+}